@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(help) = pxlclnt::protocol::parse_help(data) {
+        let _ = pxlclnt::protocol::help_mentions(&help, "OFFSET");
+    }
+});
@@ -0,0 +1,228 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt,
+};
+
+use itertools::Itertools;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::protocol;
+
+/// a color the server answered with, alpha is not part of the answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    /// parses a color in hex like the server sends it
+    /// rrggbb and rrggbbaa are accepted, the alpha is ignored
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 6 && hex.len() != 8 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        Some(Self {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        })
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+/// reads the current colors of pixels
+/// up to `depth` queries are sent before waiting for their responses, the
+/// responses are matched to the queries by their coordinates, so the order
+/// doesn't matter, queries without response are sent again after a timeout
+pub async fn read_pixels<C: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut C,
+    pixels: &[(u32, u32)],
+    depth: usize,
+) -> Result<HashMap<(u32, u32), String>, Box<dyn Error>> {
+    const RESPONSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+    const MAX_RETRIES: usize = 3;
+
+    let mut queue: VecDeque<(u32, u32)> = pixels.iter().copied().unique().collect();
+    let mut pending: HashSet<(u32, u32)> = HashSet::new();
+    let mut retries: HashMap<(u32, u32), usize> = HashMap::new();
+    let mut colors = HashMap::with_capacity(queue.len());
+
+    loop {
+        // send "PX x y" until the pipeline is full
+        let mut queries = String::new();
+        while pending.len() < depth.max(1) {
+            let Some((x, y)) = queue.pop_front() else {
+                break;
+            };
+            queries.push_str(&format!("PX {x} {y}\n"));
+            pending.insert((x, y));
+        }
+        if pending.is_empty() {
+            break;
+        }
+        stream.write_all(queries.as_bytes()).await?;
+        stream.flush().await?;
+
+        // receive "PX x y colorInHex"
+        let mut line = String::with_capacity(32);
+        match tokio::time::timeout(RESPONSE_TIMEOUT, stream.read_line(&mut line)).await {
+            Ok(Ok(0)) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed while reading pixels",
+                )
+                .into())
+            }
+            Ok(Ok(_)) => {
+                // banners and responses to queries that were already answered are skipped
+                if let Ok((x, y, color)) = protocol::parse_pixel(&line) {
+                    if pending.remove(&(x, y)) {
+                        colors.insert((x, y), color.to_string());
+                    }
+                }
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => {
+                // the responses got lost, ask again
+                for pixel in pending.drain() {
+                    let tries = retries.entry(pixel).or_default();
+                    *tries += 1;
+                    if *tries > MAX_RETRIES {
+                        return Err(format!("no response to PX {} {}", pixel.0, pixel.1).into());
+                    }
+                    queue.push_front(pixel);
+                }
+            }
+        }
+    }
+    Ok(colors)
+}
+
+/// reads the colors of the region that starts at `start` and has the size `size`
+/// the grid consists of the rows of the region, `grid[y][x]` is the color of
+/// the pixel at `start.0 + x`, `start.1 + y`
+pub async fn read_region<C: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut C,
+    start: (u32, u32),
+    size: (u32, u32),
+    depth: usize,
+) -> Result<Vec<Vec<Color>>, Box<dyn Error>> {
+    let pixels: Vec<(u32, u32)> = (start.1..start.1 + size.1)
+        .cartesian_product(start.0..start.0 + size.0)
+        .map(|(y, x)| (x, y))
+        .collect();
+    let colors = read_pixels(stream, &pixels, depth).await?;
+
+    let mut grid = Vec::with_capacity(size.1 as usize);
+    for row in pixels.chunks(size.0.max(1) as usize) {
+        let mut line = Vec::with_capacity(row.len());
+        for (x, y) in row {
+            let hex = &colors[&(*x, *y)];
+            let color = Color::from_hex(hex)
+                .ok_or_else(|| protocol::ParseError::new(format!("PX {x} {y} {hex}")))?;
+            line.push(color);
+        }
+        grid.push(line);
+    }
+    Ok(grid)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{BufReader, DuplexStream};
+
+    use super::*;
+
+    /// a server on the other end of the stream that answers `answers` queries
+    /// at a time in reversed order, after a banner
+    /// the first query for `ignored` gets no answer
+    fn serve(server: DuplexStream, answers: usize, ignored: Option<(u32, u32)>) {
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            let mut ignored = ignored;
+            write.write_all(b"hello\n").await.unwrap();
+            let mut queries = vec![];
+            while let Ok(Some(line)) = lines.next_line().await {
+                let (x, y) = line[3..]
+                    .split_once(' ')
+                    .map(|(x, y)| (x.parse::<u32>().unwrap(), y.parse::<u32>().unwrap()))
+                    .unwrap();
+                if ignored.take_if(|pixel| *pixel == (x, y)).is_some() {
+                    continue;
+                }
+                queries.push((x, y));
+                if queries.len() < answers {
+                    continue;
+                }
+                for (x, y) in queries.drain(..).rev() {
+                    let answer = format!("PX {x} {y} {:02x}{:02x}00\n", x * 16, y * 16);
+                    write.write_all(answer.as_bytes()).await.unwrap();
+                }
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn reads_a_region_answered_out_of_order() {
+        let (client, server) = tokio::io::duplex(1024);
+        serve(server, 4, None);
+        let mut stream = BufReader::new(client);
+        let grid = read_region(&mut stream, (1, 2), (2, 2), 4).await.unwrap();
+        let color = |r, g| Color { r, g, b: 0 };
+        assert_eq!(
+            grid,
+            [
+                [color(0x10, 0x20), color(0x20, 0x20)],
+                [color(0x10, 0x30), color(0x20, 0x30)]
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn asks_again_for_lost_responses() {
+        let (client, server) = tokio::io::duplex(1024);
+        serve(server, 1, Some((1, 0)));
+        let mut stream = BufReader::new(client);
+        let colors = read_pixels(&mut stream, &[(0, 0), (1, 0), (0, 0)], 2)
+            .await
+            .unwrap();
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors[&(1, 0)], "100000");
+    }
+
+    #[tokio::test]
+    async fn fails_if_the_connection_closes() {
+        let (client, server) = tokio::io::duplex(1024);
+        drop(server);
+        let mut stream = BufReader::new(client);
+        assert!(read_pixels(&mut stream, &[(0, 0)], 1).await.is_err());
+    }
+
+    #[test]
+    fn parses_colors_of_responses() {
+        let color = Color::from_hex("0a0B0c").unwrap();
+        assert_eq!(
+            color,
+            Color {
+                r: 10,
+                g: 11,
+                b: 12
+            }
+        );
+        assert_eq!(Color::from_hex("0a0b0cff"), Some(color));
+        assert_eq!(color.to_string(), "0a0b0c");
+        for hex in ["", "0a0b", "0a0b0c0", "zz0b0c", "0a0b0c0d0e0f"] {
+            assert_eq!(Color::from_hex(hex), None, "{hex}");
+        }
+    }
+}
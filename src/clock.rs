@@ -0,0 +1,265 @@
+use std::{
+    error::Error,
+    f64::consts::TAU,
+    time::{Duration, SystemTime},
+};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::{reconnect, sink, size, text, Arguments, Clock, ClockStyle, Pixel};
+
+/// seconds of a day
+const DAY: u64 = 24 * 60 * 60;
+
+/// the pixels of a clock, true for the pixels in the color of the clock
+struct Grid {
+    width: u32,
+    height: u32,
+    set: Vec<bool>,
+}
+
+impl Grid {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            set: vec![false; (width * height) as usize],
+        }
+    }
+
+    /// sets a pixel, pixels outside of the grid are ignored
+    fn plot(&mut self, x: i64, y: i64) {
+        if (0..self.width as i64).contains(&x) && (0..self.height as i64).contains(&y) {
+            self.set[(y as u32 * self.width + x as u32) as usize] = true;
+        }
+    }
+
+    /// draws a line with the algorithm of bresenham
+    fn line(&mut self, (mut x, mut y): (i64, i64), (to_x, to_y): (i64, i64)) {
+        let (dx, dy) = ((to_x - x).abs(), -(to_y - y).abs());
+        let (step_x, step_y) = ((to_x - x).signum(), (to_y - y).signum());
+        let mut error = dx + dy;
+        loop {
+            self.plot(x, y);
+            if (x, y) == (to_x, to_y) {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// draws the outline of a circle with the midpoint algorithm
+    fn circle(&mut self, (cx, cy): (i64, i64), radius: i64) {
+        let (mut x, mut y, mut error) = (radius, 0, 1 - radius);
+        while x >= y {
+            for (dx, dy) in [(x, y), (y, x), (-y, x), (-x, y)] {
+                self.plot(cx + dx, cy + dy);
+                self.plot(cx - dx, cy - dy);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+}
+
+/// returns the end of a hand that points at a fraction of a full turn,
+/// clockwise from 12 o'clock
+fn hand(center: (i64, i64), length: f64, turn: f64) -> (i64, i64) {
+    let angle = turn * TAU;
+    (
+        center.0 + (length * angle.sin()).round() as i64,
+        center.1 - (length * angle.cos()).round() as i64,
+    )
+}
+
+/// a dial with ticks for the hours and hands for hours, minutes and seconds
+/// the dial is --size pixels wide and high
+fn analog(size: u32, seconds: u64) -> Grid {
+    let mut grid = Grid::new(size, size);
+    let radius = (size.saturating_sub(1) / 2) as i64;
+    let center = (radius, radius);
+    let radius = radius as f64;
+    grid.circle(center, radius as i64);
+    for hour in 0..12 {
+        let turn = hour as f64 / 12.0;
+        grid.line(
+            hand(center, radius * 0.85, turn),
+            hand(center, radius, turn),
+        );
+    }
+
+    let seconds = seconds % (DAY / 2);
+    let hands = [
+        (0.5, seconds as f64 / (DAY / 2) as f64),
+        (0.75, (seconds % 3600) as f64 / 3600.0),
+        (0.9, (seconds % 60) as f64 / 60.0),
+    ];
+    for (length, turn) in hands {
+        grid.line(center, hand(center, radius * length, turn));
+    }
+    grid
+}
+
+/// the time as HH:MM:SS, every pixel of a character is scaled to a square so
+/// the characters are about --size pixels high
+fn digital(size: u32, seconds: u64) -> Grid {
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    let scale = (size / text::GLYPH_SIZE).max(1);
+    let columns = time.len() as u32;
+    let mut grid = Grid::new(columns * text::GLYPH_SIZE * scale, text::GLYPH_SIZE * scale);
+    text::render(&[time], columns, |x, y, set| {
+        if !set {
+            return;
+        }
+        for dy in 0..scale {
+            for dx in 0..scale {
+                grid.plot((x * scale + dx) as i64, (y * scale + dy) as i64);
+            }
+        }
+    });
+    grid
+}
+
+/// the seconds shown by the clock, the time of day or the rest of the countdown
+fn shown(clock: &Clock, started: SystemTime) -> u64 {
+    let now = SystemTime::now();
+    match clock.countdown {
+        Some(countdown) => {
+            let elapsed = now.duration_since(started).unwrap_or_default();
+            countdown.saturating_sub(elapsed.as_secs())
+        }
+        None => {
+            let since_epoch = now
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64;
+            (since_epoch + clock.utc_offset * 3600).rem_euclid(DAY as i64) as u64
+        }
+    }
+}
+
+/// paints the clock every second, only the pixels that changed are sent again
+/// a countdown ends when it reaches zero
+pub async fn run(args: &Arguments, clock: &Clock) -> Result<(), Box<dyn Error>> {
+    let canvas_limit = size(args).await?;
+    let mut stream = sink::connect_recorded(args).await?;
+    let started = SystemTime::now();
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    let mut previous: Option<Grid> = None;
+    loop {
+        ticks.tick().await;
+        let seconds = shown(clock, started);
+        let grid = match clock.style {
+            ClockStyle::Analog => analog(clock.size, seconds),
+            ClockStyle::Digital => digital(clock.size, seconds),
+        };
+
+        // e.g. a countdown with fewer digits changes the size of the grid
+        previous = previous.filter(|previous| previous.width == grid.width);
+        let mut cmds = vec![];
+        for (i, set) in grid.set.iter().enumerate() {
+            if previous
+                .as_ref()
+                .is_some_and(|previous| previous.set[i] == *set)
+            {
+                continue;
+            }
+            let (x, y) = (
+                clock.x + i as u32 % grid.width,
+                clock.y + i as u32 / grid.width,
+            );
+            if x < canvas_limit.0 && y < canvas_limit.1 {
+                let color = match set {
+                    true => clock.color.clone(),
+                    false => clock.background.clone(),
+                };
+                cmds.extend(Pixel { x, y, color }.encode(args));
+            }
+        }
+        let written = match stream.write_all(&cmds).await {
+            Ok(()) => stream.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            // the new connection could paint on a canvas that was cleared
+            if reconnect(args, &mut stream, &e).await {
+                previous = None;
+                continue;
+            }
+            return Err(e.into());
+        }
+        previous = Some(grid);
+
+        if clock.countdown.is_some() && seconds == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_set(grid: &Grid, x: u32, y: u32) -> bool {
+        grid.set[(y * grid.width + x) as usize]
+    }
+
+    #[test]
+    fn points_the_hands_at_the_time() {
+        // 3 o'clock, the hour hand points right, minute and second hand up
+        let grid = analog(21, 3 * 3600);
+        assert_eq!((grid.width, grid.height), (21, 21));
+        for x in 10..=15 {
+            assert!(is_set(&grid, x, 10), "hour hand at {x}");
+        }
+        for y in 1..=10 {
+            assert!(is_set(&grid, 10, y), "minute and second hand at {y}");
+        }
+        // no hand points left or down
+        assert!(!is_set(&grid, 5, 10));
+        assert!(!is_set(&grid, 10, 15));
+        // the dial and the tick of 6 o'clock
+        assert!(is_set(&grid, 0, 10));
+        assert!(is_set(&grid, 10, 19));
+        // the same time in the afternoon
+        assert_eq!(analog(21, 15 * 3600).set, grid.set);
+    }
+
+    #[test]
+    fn scales_the_digits_of_the_time() {
+        let mut expected = Grid::new(8 * text::GLYPH_SIZE, text::GLYPH_SIZE);
+        text::render(&["01:02:03".to_string()], 8, |x, y, set| {
+            if set {
+                expected.plot(x as i64, y as i64);
+            }
+        });
+        assert_eq!(digital(text::GLYPH_SIZE, 3723).set, expected.set);
+
+        let scaled = digital(2 * text::GLYPH_SIZE, 3723);
+        assert_eq!((scaled.width, scaled.height), (16 * text::GLYPH_SIZE, 16));
+        for y in 0..scaled.height {
+            for x in 0..scaled.width {
+                assert_eq!(is_set(&scaled, x, y), is_set(&expected, x / 2, y / 2));
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::{sink, Arguments, LoadTest};
+
+/// a step counts as plateau if it doesn't increase the throughput by this factor
+const PLATEAU_GAIN: f64 = 1.05;
+
+/// a step counts as error spike if more than this fraction of connections failed
+const ERROR_SPIKE: f64 = 0.1;
+
+#[derive(Default)]
+struct Counters {
+    bytes: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// ramps up the number of connections that send the payload until the
+/// throughput of the server stops growing or connections start to fail
+pub async fn run(
+    args: &Arguments,
+    test: &LoadTest,
+    payload: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    println!("{}", ramp(args, test, payload).await);
+    Ok(())
+}
+
+/// runs the steps of the load test and returns where the server saturated
+async fn ramp(args: &Arguments, test: &LoadTest, payload: Vec<u8>) -> String {
+    let counters = Arc::new(Counters::default());
+    let payload = Arc::new(payload);
+    let started = Instant::now();
+    let mut workers = vec![];
+    let mut best: Option<(usize, f64)> = None;
+
+    let result = loop {
+        let connections = (workers.len() + test.step.max(1)).min(test.max_connections);
+        while workers.len() < connections {
+            let args = args.clone();
+            let counters = counters.clone();
+            let payload = payload.clone();
+            workers.push(tokio::spawn(async move {
+                let Ok(mut stream) = sink::connect(&args).await else {
+                    counters.errors.fetch_add(1, Ordering::Relaxed);
+                    return;
+                };
+                while stream.write_all(&payload).await.is_ok() {
+                    counters
+                        .bytes
+                        .fetch_add(payload.len() as u64, Ordering::Relaxed);
+                }
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+
+        let bytes = counters.bytes.load(Ordering::Relaxed);
+        let errors = counters.errors.load(Ordering::Relaxed);
+        let step_start = Instant::now();
+        tokio::time::sleep(Duration::from_secs(test.step_duration)).await;
+        let throughput = (counters.bytes.load(Ordering::Relaxed) - bytes) as f64
+            / step_start.elapsed().as_secs_f64();
+        let errors = counters.errors.load(Ordering::Relaxed) - errors;
+        println!("connections: {connections} throughput: {throughput:.0} bytes/s errors: {errors}");
+
+        if errors as f64 > connections as f64 * ERROR_SPIKE {
+            break format!("errors spike at {connections} connections");
+        }
+        if let Some((count, rate)) = best {
+            if throughput < rate * PLATEAU_GAIN {
+                break format!("throughput plateaus at {count} connections ({rate:.0} bytes/s)");
+            }
+        }
+        best = Some((connections, throughput));
+
+        if connections == test.max_connections {
+            break format!("no saturation up to {connections} connections");
+        }
+        if started.elapsed() >= Duration::from_secs(test.max_duration) {
+            break format!("no saturation within {}s", test.max_duration);
+        }
+    };
+
+    for worker in workers {
+        worker.abort();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use clap::Parser;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+    use crate::Command;
+
+    #[tokio::test]
+    async fn reports_the_errors_above_the_connection_limit() {
+        // a server that closes every connection beyond the second one
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let open = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                if open.load(Ordering::SeqCst) >= 2 {
+                    continue;
+                }
+                open.fetch_add(1, Ordering::SeqCst);
+                let open = open.clone();
+                tokio::spawn(async move {
+                    let mut buffer = vec![0; 1 << 16];
+                    while matches!(socket.read(&mut buffer).await, Ok(1..)) {}
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        let args = Arguments::try_parse_from(
+            format!("pxlclnt -d {address} -t 1 -s 1 load-test --step 2 --step-duration 1")
+                .split_whitespace(),
+        )
+        .unwrap();
+        let Command::LoadTest(test) = &args.command else {
+            unreachable!()
+        };
+        let payload = b"PX 0 0 ff0000\n".repeat(64);
+        assert_eq!(
+            ramp(&args, test, payload).await,
+            "errors spike at 4 connections"
+        );
+    }
+}
@@ -1,16 +1,35 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, FromArgMatches, Parser, Subcommand, ValueEnum};
 use itertools::Itertools;
+use pxlclnt::{
+    canvas::{self, read_pixels, Color},
+    protocol,
+};
+use rand::seq::SliceRandom;
+use sink::{Connection, Sink, Throttle};
 use std::{
+    collections::VecDeque,
     error::Error,
-    path::PathBuf,
-    sync::{Arc, RwLock},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
 };
-use tokio::{io::AsyncBufReadExt, io::AsyncWriteExt, io::BufReader, net::TcpStream};
+use tokio::{io::AsyncBufReadExt, io::AsyncWriteExt, io::BufReader};
+
+mod clock;
+mod loadtest;
+mod palette;
+mod picture;
+mod script;
+mod sink;
+mod text;
+
+/// colors extracted by --palette-from by default and by the estimate of --dryrun-stats
+const PALETTE_SIZE: u16 = 16;
 
 #[derive(Debug, Clone, Args)]
 pub struct Pixel {
     x: u32,
     y: u32,
+    #[arg(value_parser = parse_color)]
     color: String,
 }
 
@@ -20,13 +39,218 @@ pub struct Rect {
     start_y: u32,
     end_x: u32,
     end_y: u32,
+    #[arg(value_parser = parse_color)]
     color: String,
+    /// vary every channel of every pixel randomly by up to this amount
+    /// dithers the fill on displays with banding
+    #[arg(long, default_value_t = 0)]
+    noise: u8,
 }
 
 #[derive(Debug, Clone, Args)]
 pub struct Image {
     x: u32,
     y: u32,
+    /// path to the image or a data uri like data:image/png;base64,...
+    path: PathBuf,
+    /// format of the image like png or jpg, by default it is recognized
+    #[arg(long, value_parser = parse_format)]
+    format: Option<image::ImageFormat>,
+    /// scale the image so its larger side covers this fraction of the canvas
+    /// and center it, x and y are added to the centered position
+    #[arg(long, value_parser = parse_fraction)]
+    cover_fraction: Option<f64>,
+    /// reduce the colors to a palette extracted from this image
+    #[arg(long)]
+    palette_from: Option<PathBuf>,
+    /// number of colors extracted by --palette-from
+    #[arg(long, default_value_t = PALETTE_SIZE, value_parser = clap::value_parser!(u16).range(1..))]
+    palette_size: u16,
+    /// paint the pixels in random order
+    #[arg(long)]
+    shuffle: bool,
+    /// vary every channel of every pixel randomly by up to this amount
+    #[arg(long, default_value_t = 0)]
+    noise: u8,
+    /// how the image is divided between the threads
+    #[arg(long, value_enum, default_value_t = SplitMode::Rows)]
+    split_mode: SplitMode,
+    /// append the pixels that were sent to this file every second
+    /// works only with still images and without loops
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// skip the pixels that are stored in this checkpoint
+    #[arg(long)]
+    resume: Option<PathBuf>,
+    /// treat the image as a sprite sheet with COLUMNSxROWS cells that are
+    /// played as frames row by row
+    #[arg(long, value_parser = parse_sheet, value_name = "COLUMNSxROWS")]
+    sheet: Option<(u32, u32)>,
+    /// blend transparent pixels with this background color instead of
+    /// dropping their alpha, for canvases with a known background
+    #[arg(long, value_parser = parse_color)]
+    over: Option<String>,
+    /// place the image at this edge or corner of the canvas, x or y is ignored
+    /// for the axis that is aligned
+    #[arg(long, value_enum)]
+    align: Option<Align>,
+    /// pixels between the image and the edges of --align
+    #[arg(long, default_value_t = 0)]
+    margin: u32,
+    /// how an animation changes from one frame to the next
+    #[arg(long, value_enum, default_value_t = Transition::None)]
+    transition: Transition,
+    /// number of frames a transition takes, the last one completes the new frame
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+    transition_frames: u32,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+    Top,
+    Bottom,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    /// the middle of the canvas, without margin
+    Center,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// every frame is painted at once
+    None,
+    /// the new frame is painted column by column over the old one
+    Wipe,
+    /// the old frame is blended into the new one
+    Fade,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitMode {
+    /// every thread paints consecutive rows
+    Rows,
+    /// every thread paints consecutive columns
+    Columns,
+    /// the image is cut into a grid of at least as many blocks as threads,
+    /// every thread paints the same number of pixels of consecutive blocks,
+    /// so a part can end in the middle of a block
+    Quadrants,
+    /// the image is cut into small tiles that are dealt to the threads
+    Tiles,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Console {
+    x: u32,
+    y: u32,
+    /// characters per line, longer lines are wrapped
+    #[arg(long, default_value_t = 80)]
+    columns: u32,
+    /// number of lines shown, older lines scroll out
+    #[arg(long, default_value_t = 24)]
+    rows: usize,
+    #[arg(long, default_value = "ffffff", value_parser = parse_color)]
+    color: String,
+    #[arg(long, default_value = "000000", value_parser = parse_color)]
+    background: String,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct LoadTest {
+    /// upper limit of concurrent connections
+    #[arg(long, default_value_t = 64)]
+    max_connections: usize,
+    /// connections added after every step
+    #[arg(long, default_value_t = 4)]
+    step: usize,
+    /// seconds every step is measured
+    #[arg(long, default_value_t = 2)]
+    step_duration: u64,
+    /// upper limit of seconds the test runs
+    #[arg(long, default_value_t = 60)]
+    max_duration: u64,
+    /// color of the 32x32 square in the upper left corner every connection paints
+    #[arg(long, default_value = "000000", value_parser = parse_color)]
+    color: String,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Defend {
+    start_x: u32,
+    start_y: u32,
+    end_x: u32,
+    end_y: u32,
+    /// color the region should have
+    #[arg(long, value_parser = parse_color, required_unless_present = "image")]
+    color: Option<String>,
+    /// image the region should show, its upper left corner is at start_x start_y
+    #[arg(long, conflicts_with = "color")]
+    image: Option<PathBuf>,
+    /// milliseconds to wait between two checks of the region
+    #[arg(long, default_value_t = 0)]
+    interval: u64,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Territory {
+    x: u32,
+    y: u32,
+    path: PathBuf,
+    /// number of random pixels of the image that are compared per report
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+    samples: u32,
+    /// seconds between two reports
+    #[arg(long, default_value_t = 5)]
+    interval: u64,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Clock {
+    x: u32,
+    y: u32,
+    #[arg(long, value_enum, default_value_t = ClockStyle::Digital)]
+    style: ClockStyle,
+    /// diameter of the analog clock or height of the digits in pixels
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(8..))]
+    size: u32,
+    /// count down from this many seconds instead of showing the time
+    #[arg(long)]
+    countdown: Option<u64>,
+    /// hours between the shown time and UTC
+    #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+    utc_offset: i64,
+    #[arg(long, default_value = "ffffff", value_parser = parse_color)]
+    color: String,
+    #[arg(long, default_value = "000000", value_parser = parse_color)]
+    background: String,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockStyle {
+    /// a dial with hands
+    Analog,
+    /// the time as HH:MM:SS
+    Digital,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Script {
+    /// json file with a list of steps, see the readme
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Replay {
+    /// file written with --record
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Undo {
     path: PathBuf,
 }
 
@@ -41,7 +265,7 @@ pub struct Arguments {
 
     /// how many threads should be used
     /// works only with image
-    #[arg(short, long)]
+    #[arg(short, long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
     threads: usize,
 
     /// should the programm loop indefinetly
@@ -50,7 +274,7 @@ pub struct Arguments {
 
     /// how many commands should be send with one request
     /// works only with image
-    #[arg(short, long)]
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
     size: u32,
 
     /// give the size of the canvas if size is not supported
@@ -58,6 +282,226 @@ pub struct Arguments {
     canvas_x: Option<u32>,
     #[arg(long)]
     canvas_y: Option<u32>,
+
+    /// store the colors that get overwritten in this file
+    /// they can be restored with undo
+    #[arg(long)]
+    history: Option<PathBuf>,
+
+    /// maximum number of pixels stored in the history
+    #[arg(long, default_value_t = 1_000_000)]
+    history_limit: usize,
+
+    /// append the commands that paint to this file, it can be sent again
+    /// with replay
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// store when every write of --record was sent, so replay keeps the timing
+    #[arg(long, requires = "record")]
+    record_timed: bool,
+
+    /// how many pixels are queried before waiting for the responses
+    /// only for servers that answer queries in a pipeline
+    #[arg(long, default_value_t = 1)]
+    pipeline: usize,
+
+    /// print the commands instead of sending them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// print the number of commands and bytes, the estimated duration and the
+    /// most used colors of a pass instead of sending them
+    #[arg(long)]
+    dryrun_stats: bool,
+
+    /// write the commands divided into N files named PREFIX0 to PREFIX<N-1>
+    /// instead of sending them, every file can be sent by a different client
+    #[arg(long, num_args = 2, value_names = ["PREFIX", "N"])]
+    split_output: Option<Vec<String>>,
+
+    /// print the most used colors of the commands before sending them
+    /// works with pixel, rect and image
+    #[arg(long, conflicts_with = "max_buffer_bytes")]
+    output_commands_stats: bool,
+
+    /// number of the most used colors listed by --dryrun-stats and
+    /// --output-commands-stats
+    #[arg(long, default_value_t = 10)]
+    top_colors: usize,
+
+    /// bytes per second used for the estimation of --dryrun-stats
+    #[arg(long, default_value_t = 125_000_000)]
+    rate: u64,
+
+    /// paint at most this many frames per second, frames that can't be painted
+    /// in time are dropped
+    /// works only with image
+    #[arg(long, value_parser = parse_framerate)]
+    framerate: Option<f64>,
+
+    /// make runs reproducible, random numbers use a fixed seed and --framerate
+    /// paints late frames instead of dropping them
+    /// affects --shuffle, --noise, --framerate and the samples of territory
+    #[arg(long)]
+    deterministic: bool,
+
+    /// seed of the random numbers, overrides the seed of --deterministic
+    /// affects --shuffle, --noise and the samples of territory
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// syntax of the commands that paint pixels
+    #[arg(long, value_enum, default_value_t = Protocol::Standard)]
+    protocol: Protocol,
+
+    /// order of the color channels the server expects
+    #[arg(long, value_enum, default_value_t = ChannelOrder::Rgb)]
+    channel_order: ChannelOrder,
+
+    /// send gray colors as ww and opaque colors without alpha, shortens the
+    /// standard commands for servers that accept these colors
+    #[arg(long)]
+    short_colors: bool,
+
+    /// send this many commands as fast as possible, then pause for --idle
+    /// milliseconds and repeat, a burst ends with the batch that reaches the number
+    #[arg(long, requires = "idle")]
+    burst: Option<usize>,
+
+    /// milliseconds to pause after every --burst
+    #[arg(long, requires = "burst")]
+    idle: Option<u64>,
+
+    /// local address the connections are sent from, e.g. to choose the
+    /// network interface
+    #[arg(long)]
+    bind: Option<std::net::IpAddr>,
+
+    /// size of the send buffer of the sockets in bytes, the default of the
+    /// operating system can limit the throughput
+    #[arg(long)]
+    send_buffer: Option<usize>,
+
+    /// send only the last command for every pixel of a pass
+    #[arg(long)]
+    dedupe: bool,
+
+    /// keep at most this many bytes of commands that are generated but not
+    /// sent yet, generating waits for the server instead of buffering more
+    /// works with script, rect and image
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_buffer_bytes: Option<usize>,
+
+    /// append these bytes to the end of every pass, for servers that process
+    /// the commands only after e.g. an empty line
+    /// escapes like \n, \r, \t, \\ and \x00 are replaced
+    #[arg(long, value_parser = parse_bytes)]
+    final_sentinel: Option<Bytes>,
+
+    /// query the size of the canvas every this many seconds and fit the image
+    /// again if it changed
+    /// works only with image
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    resize_check: Option<u64>,
+
+    /// keep trying to connect for this many seconds while the server refuses
+    /// connections, e.g. because it is full
+    #[arg(long)]
+    wait_for_server: Option<u64>,
+
+    /// connect again if the connection is lost while painting
+    /// works with image, rect, defend and clock, writes are only slowed down
+    /// when many of them fail with it
+    #[arg(long)]
+    reconnect: bool,
+
+    /// also connect again if the server closed the connection after it sent
+    /// every response, these servers close connections on purpose
+    #[arg(long)]
+    retry_on_eof: bool,
+
+    /// check that the server answers before the image is loaded
+    /// works only with image
+    #[arg(long)]
+    preconnect_check: bool,
+
+    /// read and discard the greeting the server sends after connecting, it has
+    /// to arrive within 2 seconds
+    #[arg(long)]
+    expect_banner: bool,
+
+    /// position the image with the OFFSET command of the server, image only
+    /// falls back to adding the offset to the coordinates if OFFSET is not supported
+    #[arg(long)]
+    offset: bool,
+
+    /// send the OFFSET at the start of every write instead of once per
+    /// connection, for servers that forget it
+    #[arg(long, requires = "offset")]
+    offset_per_batch: bool,
+
+    /// read the pixels back after painting and report the ones that differ
+    /// has no effect with --loops
+    #[arg(long)]
+    check: bool,
+
+    /// write the pixels that failed the check as csv to this file
+    #[arg(long, requires = "check")]
+    error_log: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// text commands like "PX 10 20 ff0000"
+    Standard,
+    /// binary commands of breakwater compiled with the binary-set-pixel feature:
+    /// "PB", x and y as little endian u16 and the color as rgba bytes
+    /// pixels with a coordinate after 65535 are dropped
+    Compact,
+    /// text commands with 16 bit per channel like "PX 10 20 ffff00000000"
+    /// 8 bit colors are widened, only for servers that list it in their HELP
+    /// or report a depth of more than 8 bit per channel in their SIZE, these
+    /// servers get it without --protocol
+    Extended,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+    Grb,
+}
+
+impl ChannelOrder {
+    /// puts red, green and blue in this order
+    fn arrange<T>(self, [r, g, b]: [T; 3]) -> [T; 3] {
+        match self {
+            ChannelOrder::Rgb => [r, g, b],
+            ChannelOrder::Bgr => [b, g, r],
+            ChannelOrder::Grb => [g, r, b],
+        }
+    }
+
+    /// reorders the channels of a color in hex, alpha stays last
+    /// gray has nothing to reorder
+    fn arrange_hex(self, color: &str) -> String {
+        let width = if color.len() >= 12 { 4 } else { 2 };
+        if self == ChannelOrder::Rgb || color.len() == width {
+            return color.to_string();
+        }
+        let (rgb, alpha) = color.split_at(3 * width);
+        let (r, gb) = rgb.split_at(width);
+        let (g, b) = gb.split_at(width);
+        self.arrange([r, g, b]).concat() + alpha
+    }
+}
+
+impl Arguments {
+    /// checks if the commands are only shown instead of sent
+    fn is_dry_run(&self) -> bool {
+        self.dry_run || self.dryrun_stats || self.split_output.is_some()
+    }
 }
 
 #[derive(Subcommand, Clone, Debug)]
@@ -67,11 +511,29 @@ pub enum Command {
     Pixel(Pixel),
     Rect(Rect),
     Image(Image),
+    /// mirrors the lines read from stdin to the canvas
+    Console(Console),
+    /// finds the number of connections that saturates the server
+    LoadTest(LoadTest),
+    /// repaints the pixels of a region that differ from a color or image
+    Defend(Defend),
+    /// estimates how much of an image is still on the canvas
+    Territory(Territory),
+    /// paints a clock or a countdown that is updated every second
+    Clock(Clock),
+    /// runs the steps of a json script in order on one connection
+    Script(Script),
+    /// sends a recording again, with the original timing if it was recorded
+    Replay(Replay),
+    Undo(Undo),
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let args = Arguments::parse();
+    let matches = <Arguments as clap::CommandFactory>::command().get_matches();
+    let mut args = Arguments::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let chosen = matches.value_source("protocol") == Some(clap::parser::ValueSource::CommandLine);
+    choose_protocol(&mut args, chosen).await;
 
     match args.command {
         Command::Howto => howto(&args).await?,
@@ -79,196 +541,2654 @@ async fn main() -> Result<(), Box<dyn Error>> {
             let size = size(&args).await?;
             println!("{size:?}");
         }
-        Command::Pixel(pxl) => {
-            let mut stream = BufReader::new(TcpStream::connect(&args.domain).await?);
-            if args.loops {
-                loop {
-                    pixel(&mut stream, &pxl.to_cmd()).await?;
-                }
-            } else {
-                pixel(&mut stream, &pxl.to_cmd()).await?;
+        Command::Pixel(ref pxl) => {
+            let mut task = vec![pxl.encode(&args)];
+            finish_pass(&args, &mut task);
+            if args.is_dry_run() {
+                dry_run(&args, &[task]).await?;
+                return Ok(());
             }
+            output_commands_stats(&args, task.iter());
+            record_history(&args, [(pxl.x, pxl.y)].into_iter()).await?;
+            let mut stream = sink::connect_recorded(&args).await?;
+            let mut throttle = Throttle::new(&args);
+            let mut progress = Progress::default();
+            paint(&mut stream, &task, args.loops, &mut throttle, &mut progress).await?;
+            stream.get_mut().pause_recording();
+            check(&args, &mut stream, std::slice::from_ref(pxl)).await?;
         }
         Command::Rect(ref rct) => rect(&args, rct).await?,
-        Command::Image(ref img) => image(&args, img).await?,
+        Command::Image(ref img) => {
+            picture::run(&args, img).await?;
+        }
+        Command::Console(ref console) => mirror_stdin(&args, console).await?,
+        Command::LoadTest(ref test) => {
+            let square = Rect {
+                start_x: 0,
+                start_y: 0,
+                end_x: 32,
+                end_y: 32,
+                color: test.color.clone(),
+                noise: 0,
+            };
+            let payload = rect_cmds(&square, &args, &mut rng(&args)).concat();
+            loadtest::run(&args, test, payload).await?;
+        }
+        Command::Defend(ref defend) => guard(&args, defend).await?,
+        Command::Territory(ref territory) => survey(&args, territory).await?,
+        Command::Clock(ref clock) => clock::run(&args, clock).await?,
+        Command::Script(ref script) => script::run(&args, script).await?,
+        Command::Replay(ref replay) => play(&args, replay).await?,
+        Command::Undo(ref undo) => restore(&args, undo).await?,
     };
 
     Ok(())
 }
 
-/// paints image with an offset
-async fn image(args: &Arguments, img: &Image) -> Result<(), Box<dyn Error>> {
-    //preparation from here till core loop
-    let image = image::open(&img.path)?.to_rgb8();
-    let canvas_limit = size(args).await?;
-    if image.width() > canvas_limit.0 || image.height() > canvas_limit.1 {
-        println!("WARN: image is bigger than canvas size");
+/// parses the cells of a sprite sheet like 4x2
+fn parse_sheet(input: &str) -> Result<(u32, u32), String> {
+    let error = || String::from("sheet has to be COLUMNSxROWS, e.g. 4x2");
+    let (columns, rows) = input.split_once('x').ok_or_else(error)?;
+    let columns: u32 = columns.parse().map_err(|_| error())?;
+    let rows: u32 = rows.parse().map_err(|_| error())?;
+    if columns == 0 || rows == 0 {
+        return Err(String::from("sheet needs at least one column and row"));
     }
+    Ok((columns, rows))
+}
 
-    // collect all pixels of image
-    let all_pixels: Vec<Pixel> = image
-        .enumerate_pixels()
-        .map(|pxl| Pixel {
-            x: img.x + pxl.0,
-            y: img.y + pxl.1,
-            color: format!(
-                "{:02x?}{:02x?}{:02x?}",
-                pxl.2 .0[0], pxl.2 .0[1], pxl.2 .0[2]
-            ),
-        })
-        .collect();
+/// random number generator for everything random
+/// with --deterministic every run gets the same numbers
+fn rng(args: &Arguments) -> rand::rngs::StdRng {
+    use rand::SeedableRng;
 
-    // divide pixels for threads
-    let mut tasks: Vec<Vec<String>> = vec![];
-    let span = all_pixels.len() / args.threads;
-    for i in 0..args.threads {
-        let pxls = &all_pixels[(span * i)..(span * (i + 1))];
-        let pxls: Vec<String> = pxls
-            .iter()
-            .filter(|pxl| pxl.x < canvas_limit.0 && pxl.y < canvas_limit.1)
-            .map(|pxl| pxl.to_cmd())
-            .collect();
-        tasks.push(pxls);
-    }
+    const DETERMINISTIC_SEED: u64 = 0x0070_786c_636c_6e74;
 
-    // group commands
-    let groups: Vec<Vec<String>> = tasks
-        .iter()
-        .map(|span| {
-            let mut result: Vec<String> = vec![];
-            let chunks = span.iter().chunks(args.size as usize);
-            chunks.into_iter().for_each(|c| {
-                let mut cmd = String::new();
-                for i in c {
-                    cmd = format!("{}{}", cmd, i);
-                }
-                result.push(cmd);
-            });
-            result
-        })
-        .collect();
+    if let Some(seed) = args.seed {
+        rand::rngs::StdRng::seed_from_u64(seed)
+    } else if args.deterministic {
+        rand::rngs::StdRng::seed_from_u64(DETERMINISTIC_SEED)
+    } else {
+        rand::rngs::StdRng::from_entropy()
+    }
+}
 
-    async fn work(domain: &str, loops: bool, task: &Vec<String>) {
-        let mut stream = BufReader::new(TcpStream::connect(domain).await.unwrap());
-        #[allow(clippy::while_immutable_condition)]
-        while loops {
-            for pxl in task {
-                pixel(&mut stream, pxl).await.unwrap();
-            }
-        }
+fn parse_fraction(input: &str) -> Result<f64, String> {
+    let fraction: f64 = input.parse().map_err(|e| format!("{e}"))?;
+    if fraction > 0.0 && fraction <= 1.0 {
+        Ok(fraction)
+    } else {
+        Err(String::from(
+            "fraction has to be greater than 0 and at most 1",
+        ))
     }
+}
 
-    // core loop
-    // spawn threads that work on pixels
-    let tasks = Arc::new(RwLock::new(groups));
-    let mut handles = vec![];
-    for i in 0..args.threads {
-        let task = tasks.clone();
-        let loops = args.loops;
-				let domain = args.domain.clone();
-        let handle = std::thread::spawn(move || {
-            tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(async {
-                    let task = task.read().unwrap()[i].clone();
-                    work(&domain, loops, &task).await;
-                })
-        });
-        handles.push(handle);
+fn parse_framerate(input: &str) -> Result<f64, String> {
+    let framerate: f64 = input.parse().map_err(|e| format!("{e}"))?;
+    if framerate > 0.0 && framerate.is_finite() {
+        Ok(framerate)
+    } else {
+        Err(String::from("framerate has to be greater than 0"))
     }
-    // wait for threads to end
-    for handle in handles {
-        let _ = handle.join();
+}
+
+/// opens an image from a file or from a base64 encoded data uri
+/// e.g. data:image/png;base64,iVBORw0KGgo...
+/// returns every frame of animated gifs
+fn load_image(path: &Path) -> Result<Vec<image::DynamicImage>, Box<dyn Error>> {
+    load_image_as(path, None)
+}
+
+/// like load_image, the format is the given one or recognized by the content
+/// of the file and only then by its extension, so misnamed files still work
+fn load_image_as(
+    path: &Path,
+    format: Option<image::ImageFormat>,
+) -> Result<Vec<image::DynamicImage>, Box<dyn Error>> {
+    use base64::Engine;
+    use image::ImageFormat;
+
+    let data = match path.to_str().and_then(|p| p.strip_prefix("data:")) {
+        Some(uri) => {
+            let Some((_media_type, data)) = uri.split_once(";base64,") else {
+                return Err("only base64 encoded data uris are supported".into());
+            };
+            base64::engine::general_purpose::STANDARD.decode(data.trim())?
+        }
+        None => std::fs::read(path)?,
+    };
+    let format = match format {
+        Some(format) => format,
+        None => image::guess_format(&data).or_else(|_| ImageFormat::from_path(path))?,
+    };
+    if format == ImageFormat::Gif {
+        return decode_gif(std::io::Cursor::new(data));
     }
+    Ok(vec![image::load_from_memory_with_format(&data, format)?])
+}
 
-    Ok(())
+/// parses an image format by its usual extension, e.g. png or jpg
+fn parse_format(input: &str) -> Result<image::ImageFormat, String> {
+    image::ImageFormat::from_extension(input).ok_or_else(|| format!("unknown image format {input}"))
+}
+
+fn decode_gif<R: std::io::Read>(reader: R) -> Result<Vec<image::DynamicImage>, Box<dyn Error>> {
+    use image::AnimationDecoder;
+
+    let frames = image::codecs::gif::GifDecoder::new(reader)?
+        .into_frames()
+        .collect_frames()?;
+    if frames.is_empty() {
+        return Err("gif has no frames".into());
+    }
+    Ok(frames
+        .into_iter()
+        .map(|frame| image::DynamicImage::ImageRgba8(frame.into_buffer()))
+        .collect())
 }
 
 /// renders a single pixel
 #[inline(always)]
-async fn pixel(stream: &mut BufReader<TcpStream>, cmd: &str) -> Result<(), Box<dyn Error>> {
+async fn pixel<S: Sink>(stream: &mut S, cmd: &[u8]) -> Result<(), Box<dyn Error>> {
     // format "PX x y colorInHex"
     // some server also allow alpha channel in the color
-    stream.write_all(cmd.as_bytes()).await?;
+    stream.write_all(cmd).await?;
+    Ok(())
+}
+
+/// what a connection sent, after a reconnect it continues where it stopped
+/// instead of sending everything again
+#[derive(Debug, Default, Clone, Copy)]
+struct Progress {
+    /// the next frame of an animation
+    frame: usize,
+    /// the next batch of the current task, 0 once a task is done
+    batch: usize,
+    /// commands and bytes of the batches that were sent, a batch that is sent
+    /// again after a reconnect is counted once
+    commands: usize,
+    bytes: usize,
+    /// commands that were sent again because they could have been lost
+    resent: usize,
+    reconnects: usize,
+    /// the connection was lost and could not be opened again
+    stopped: bool,
+}
+
+impl Progress {
+    fn sent(&mut self, cmd: &[u8], commands: usize) {
+        self.commands += commands;
+        self.bytes += cmd.len();
+    }
+
+    /// takes back a sent batch that is sent again, so it isn't counted twice
+    fn resend(&mut self, cmd: &[u8]) {
+        let commands = protocol::split_commands(cmd).count();
+        self.commands = self.commands.saturating_sub(commands);
+        self.bytes = self.bytes.saturating_sub(cmd.len());
+        self.resent += commands;
+    }
+
+    /// goes back to the first batch of the task whose bytes could have been
+    /// lost with a connection that buffered this many bytes
+    fn rewind(&mut self, task: &[Vec<u8>], mut bytes: usize) {
+        while self.batch > 0 && bytes > 0 {
+            self.batch -= 1;
+            self.resend(&task[self.batch]);
+            bytes = bytes.saturating_sub(task[self.batch].len());
+        }
+    }
+}
+
+/// sends the commands of a task once or, if loops is set, indefinitely
+/// the writes are slowed down by the throttle when too many of them fail,
+/// the failures are only counted with --reconnect, otherwise the first one
+/// ends the task
+/// a task that failed before continues at the batch that failed
+async fn paint<S: Sink>(
+    stream: &mut S,
+    task: &[Vec<u8>],
+    loops: bool,
+    throttle: &mut Throttle,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        while let Some(cmd) = task.get(progress.batch) {
+            throttle.wait().await;
+            let written = pixel(stream, cmd).await;
+            let commands = protocol::split_commands(cmd).count();
+            throttle.record(written.is_ok(), commands);
+            written?;
+            progress.sent(cmd, commands);
+            progress.batch += 1;
+        }
+        progress.batch = 0;
+        if !loops {
+            break;
+        }
+    }
+    stream.flush().await?;
     Ok(())
 }
 
 /// renders a simple rect single threaded
 async fn rect(args: &Arguments, rect: &Rect) -> Result<(), Box<dyn Error>> {
-    let mut stream = BufReader::new(TcpStream::connect(&args.domain).await?);
-
-    #[allow(clippy::while_immutable_condition)]
-    while args.loops {
-        let pixel = String::from("PX ");
-        for x in rect.start_x..rect.end_x {
-            for y in rect.start_y..rect.end_y {
-                // appending on string is slightly faster than format!()
-                let mut s = pixel.clone();
-                s.push_str(&x.to_string());
-                s.push(' ');
-                s.push_str(&y.to_string());
-                s.push(' ');
-                s.push_str(&rect.color);
-                s.push('\n');
-                stream.write_all(s.as_bytes()).await?;
+    let mut rng = rng(args);
+    // --check draws the same noise again, in the order of rect_cmds
+    let mut noise = rng.clone();
+    if args.is_dry_run() {
+        let mut cmds = rect_cmds(rect, args, &mut rng).collect();
+        finish_pass(args, &mut cmds);
+        return dry_run(args, &[cmds]).await;
+    }
+
+    record_history(
+        args,
+        (rect.start_x..rect.end_x).cartesian_product(rect.start_y..rect.end_y),
+    )
+    .await?;
+
+    let mut stream = sink::connect_recorded(args).await?;
+    let mut throttle = Throttle::new(args);
+    match args.max_buffer_bytes {
+        Some(cap) => {
+            paint_rect_streamed(args, rect, &mut stream, &mut rng, cap, &mut throttle).await?
+        }
+        None => {
+            let mut cmds = rect_cmds(rect, args, &mut rng).collect();
+            finish_pass(args, &mut cmds);
+            output_commands_stats(args, cmds.iter());
+            let mut progress = Progress::default();
+            while let Err(e) =
+                paint(&mut stream, &cmds, args.loops, &mut throttle, &mut progress).await
+            {
+                if !reconnect(args, &mut stream, &*e).await {
+                    return Err(e);
+                }
             }
         }
     }
-    Ok(())
+    stream.get_mut().pause_recording();
+
+    let painted: Vec<Pixel> = (rect.start_x..rect.end_x)
+        .cartesian_product(rect.start_y..rect.end_y)
+        .map(|(x, y)| Pixel {
+            x,
+            y,
+            color: noisy(&rect.color, rect.noise, &mut noise),
+        })
+        .collect();
+    check(args, &mut stream, &painted).await
 }
 
-/// prints the HELP command to the pixelflut server
-async fn howto(args: &Arguments) -> Result<(), Box<dyn Error>> {
-    let mut stream = BufReader::new(TcpStream::connect(&args.domain).await?);
+/// paints a rect in parts of at most --max-buffer-bytes, the commands of a
+/// part are generated once the previous part was sent
+async fn paint_rect_streamed(
+    args: &Arguments,
+    rect: &Rect,
+    stream: &mut BufReader<sink::Socket>,
+    rng: &mut impl rand::Rng,
+    cap: usize,
+    throttle: &mut Throttle,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        for (mut part, last) in parts(rect_cmds(rect, args, rng), cap) {
+            // the pixels of a rect are unique, so only the sentinel ends the pass
+            if last {
+                finish_pass(args, &mut part);
+            }
+            let mut progress = Progress::default();
+            while let Err(e) = paint(stream, &part, false, throttle, &mut progress).await {
+                if !reconnect(args, stream, &*e).await {
+                    return Err(e);
+                }
+            }
+        }
+        if !args.loops {
+            return Ok(());
+        }
+    }
+}
 
-    // send HELP
-    stream.write_all(b"HELP\n").await?;
+/// cuts the commands into parts of at most cap bytes, a command bigger than
+/// cap is a part of its own, and tells if the part is the last one
+/// the commands of a part are generated only when the part is taken
+fn parts(
+    cmds: impl Iterator<Item = Vec<u8>>,
+    cap: usize,
+) -> impl Iterator<Item = (Vec<Vec<u8>>, bool)> {
+    let mut cmds = cmds.peekable();
+    std::iter::from_fn(move || {
+        let mut part = vec![];
+        let mut bytes = 0;
+        while let Some(cmd) = cmds.next_if(|cmd| part.is_empty() || bytes + cmd.len() <= cap) {
+            bytes += cmd.len();
+            part.push(cmd);
+        }
+        (!part.is_empty()).then(|| (part, cmds.peek().is_none()))
+    })
+}
 
-    // receive
-    let mut buffer = String::with_capacity(256);
-    stream.read_line(&mut buffer).await?;
-    println!("{buffer:?}");
-    Ok(())
+/// drops the commands that a later command of the pass overwrites with
+/// --dedupe and appends the --final-sentinel to the last batch of a pass
+fn finish_pass(args: &Arguments, task: &mut Vec<Vec<u8>>) {
+    if args.dedupe {
+        *task = protocol::dedupe(task);
+    }
+    if let (Some(Bytes(sentinel)), Some(last)) = (&args.final_sentinel, task.last_mut()) {
+        last.extend_from_slice(sentinel);
+    }
 }
 
-/// query the size of the pixelflut server canvas
-async fn size(args: &Arguments) -> Result<(u32, u32), Box<dyn Error>> {
-    if let (Some(x), Some(y)) = (args.canvas_x, args.canvas_y) {
-        return Ok((x, y));
+/// builds the commands of a rect, one command per pixel
+/// the commands are generated while the iterator is consumed
+fn rect_cmds<'a, R: rand::Rng>(
+    rect: &'a Rect,
+    args: &'a Arguments,
+    rng: &'a mut R,
+) -> impl Iterator<Item = Vec<u8>> + 'a {
+    let pixel = String::from("PX ");
+    let fill = match args.short_colors {
+        true => shorten(&rect.color),
+        false => rect.color.clone(),
+    };
+    (rect.start_x..rect.end_x)
+        .cartesian_product(rect.start_y..rect.end_y)
+        .map(move |(x, y)| {
+            if args.protocol != Protocol::Standard
+                || args.channel_order != ChannelOrder::Rgb
+                || rect.noise > 0
+            {
+                let color = noisy(&rect.color, rect.noise, rng);
+                return Pixel { x, y, color }.encode(args);
+            }
+            // appending on string is slightly faster than format!()
+            let mut s = pixel.clone();
+            s.push_str(&x.to_string());
+            s.push(' ');
+            s.push_str(&y.to_string());
+            s.push(' ');
+            s.push_str(&fill);
+            s.push('\n');
+            s.into_bytes()
+        })
+}
+
+/// the color with noise added to its red, green and blue channel
+fn noisy(color: &str, amount: u8, rng: &mut impl rand::Rng) -> String {
+    if amount == 0 {
+        return color.to_string();
     }
+    let mut channels = rgba(color);
+    add_noise(&mut channels[..3], amount, rng);
+    channels[..color.len().max(6) / 2]
+        .iter()
+        .map(|channel| format!("{channel:02x}"))
+        .collect()
+}
 
-    let mut stream = BufReader::new(TcpStream::connect(&args.domain).await?);
+/// changes every channel randomly by up to amount in either direction
+fn add_noise(channels: &mut [u8], amount: u8, rng: &mut impl rand::Rng) {
+    for channel in channels {
+        let delta = rng.gen_range(-(amount as i16)..=amount as i16);
+        *channel = (*channel as i16 + delta).clamp(0, 255) as u8;
+    }
+}
 
-    // send SIZE
-    stream.write_all(b"SIZE\n").await?;
+/// reads the region and repaints only the pixels that differ from the reference
+/// checks once or, if loops is set, indefinitely
+async fn guard(args: &Arguments, defend: &Defend) -> Result<(), Box<dyn Error>> {
+    let canvas_limit = size(args).await?;
+    let reference = match &defend.image {
+        Some(path) => Some(load_image(path)?.swap_remove(0).to_rgb8()),
+        None => None,
+    };
 
-    // receive
-    let mut buffer = String::with_capacity(32);
-    stream.read_line(&mut buffer).await?;
+    // only the part that is on the canvas and covered by the image is defended
+    let mut end = (
+        defend.end_x.min(canvas_limit.0),
+        defend.end_y.min(canvas_limit.1),
+    );
+    if let Some(image) = &reference {
+        end.0 = end.0.min(defend.start_x + image.width());
+        end.1 = end.1.min(defend.start_y + image.height());
+    }
+    let width = end.0.saturating_sub(defend.start_x);
+    let height = end.1.saturating_sub(defend.start_y);
+    if width == 0 || height == 0 {
+        return Err("no pixel of the region is on the canvas".into());
+    }
+    let expected = |x: u32, y: u32| -> [u8; 3] {
+        match (&reference, &defend.color) {
+            (Some(image), _) => image.get_pixel(x, y).0,
+            (None, Some(color)) => rgba(color)[..3].try_into().unwrap(),
+            (None, None) => unreachable!("clap requires color or image"),
+        }
+    };
 
-    fn parse(input: &str) -> nom::IResult<&str, (u32, u32)> {
-        use nom::{
-            bytes::complete::take,
-            character::complete,
-            sequence::{separated_pair, terminated},
+    // only the repainted pixels are recorded, not reading the region
+    let mut stream = sink::connect(args).await?;
+    loop {
+        stream.get_mut().pause_recording();
+        let region = match canvas::read_region(
+            &mut stream,
+            (defend.start_x, defend.start_y),
+            (width, height),
+            args.pipeline,
+        )
+        .await
+        {
+            Ok(region) => region,
+            Err(e) if reconnect(args, &mut stream, &*e).await => continue,
+            Err(e) => return Err(e),
+        };
+        let mut cmds = vec![];
+        for (y, row) in (0..).zip(region.iter()) {
+            for (x, color) in (0..).zip(row.iter()) {
+                let [r, g, b] = expected(x, y);
+                // the server answers in its channel order
+                let [sr, sg, sb] = args.channel_order.arrange([r, g, b]);
+                if *color
+                    != (Color {
+                        r: sr,
+                        g: sg,
+                        b: sb,
+                    })
+                {
+                    let pxl = Pixel {
+                        x: defend.start_x + x,
+                        y: defend.start_y + y,
+                        color: format!("{r:02x}{g:02x}{b:02x}"),
+                    };
+                    cmds.extend(pxl.encode(args));
+                }
+            }
+        }
+        stream.get_mut().record(args)?;
+        let written = match stream.write_all(&cmds).await {
+            Ok(()) => stream.flush().await,
+            Err(e) => Err(e),
         };
-        let (rest, _) = take(5u8)(input)?;
-        let (rest, parsed) = separated_pair(
-            complete::u32,
-            complete::space1,
-            terminated(complete::u32, complete::newline),
-        )(rest)?;
-        Ok((rest, parsed))
+        if let Err(e) = written {
+            if reconnect(args, &mut stream, &e).await {
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        if !args.loops {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(defend.interval)).await;
+        if sink::closed(&mut stream).await {
+            if !args.retry_on_eof {
+                println!("server closed the connection");
+                break;
+            }
+            stream = sink::connect(args).await?;
+        }
+    }
+    Ok(())
+}
+
+/// reports the fraction of randomly sampled pixels of the image that match
+/// the canvas, once or, if loops is set, indefinitely
+async fn survey(args: &Arguments, territory: &Territory) -> Result<(), Box<dyn Error>> {
+    let canvas_limit = size(args).await?;
+    let image = load_image(&territory.path)?.swap_remove(0).to_rgb8();
+    let art: Vec<((u32, u32), [u8; 3])> = image
+        .enumerate_pixels()
+        .map(|(x, y, pxl)| ((territory.x + x, territory.y + y), pxl.0))
+        .filter(|((x, y), _)| *x < canvas_limit.0 && *y < canvas_limit.1)
+        .collect();
+    if art.is_empty() {
+        return Err("no pixel of the image is on the canvas".into());
     }
 
-    let size = parse(&buffer).unwrap().1;
-    Ok(size)
+    let mut rng = rng(args);
+    let mut stream = sink::connect(args).await?;
+    loop {
+        let (owned, sampled) = sample(args, &mut stream, &art, territory.samples, &mut rng).await?;
+        println!(
+            "territory: {:.1}% ({owned} of {sampled} pixels)",
+            owned as f64 * 100.0 / sampled as f64,
+        );
+
+        if !args.loops {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(territory.interval)).await;
+    }
+    Ok(())
 }
 
-impl Pixel {
-    pub fn to_cmd(&self) -> String {
-        format!("PX {} {} {}\n", self.x, self.y, self.color)
+/// reads random pixels of the art and returns how many of them show the color
+/// of the art and how many were read
+async fn sample<C: Connection>(
+    args: &Arguments,
+    stream: &mut C,
+    art: &[((u32, u32), [u8; 3])],
+    samples: u32,
+    rng: &mut impl rand::Rng,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    let samples: Vec<&((u32, u32), [u8; 3])> = art.choose_multiple(rng, samples as usize).collect();
+    let coords: Vec<(u32, u32)> = samples.iter().map(|(pixel, _)| *pixel).collect();
+    let colors = read_pixels(stream, &coords, args.pipeline).await?;
+    let owned = samples
+        .iter()
+        .filter(|(pixel, [r, g, b])| {
+            // the server answers in its channel order
+            let [r, g, b] = args.channel_order.arrange([*r, *g, *b]);
+            Color::from_hex(&colors[pixel]) == Some(Color { r, g, b })
+        })
+        .count();
+    Ok((owned, samples.len()))
+}
+
+/// connects again after the connection failed with the error, if allowed
+/// losing the connection while responses are outstanding is reconnected with
+/// --reconnect, a server that closed the connection cleanly only with --retry-on-eof
+async fn reconnect(
+    args: &Arguments,
+    stream: &mut BufReader<sink::Socket>,
+    error: &(dyn Error + 'static),
+) -> bool {
+    let retry = match error.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+        Some(
+            std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted,
+        ) => args.reconnect,
+        _ if sink::closed(stream).await => args.retry_on_eof,
+        Some(std::io::ErrorKind::BrokenPipe) => args.reconnect,
+        _ => false,
+    };
+    if !retry {
+        return false;
+    }
+    println!("WARN: {error}, connecting again");
+    let recorded = stream.get_ref().is_recording();
+    let connection = match sink::connect(args).await {
+        Ok(mut connection) if recorded => connection.get_mut().record(args).map(|()| connection),
+        connection => connection,
+    };
+    match connection {
+        Ok(connection) => {
+            *stream = connection;
+            true
+        }
+        Err(e) => {
+            println!("WARN: could not connect again: {e}");
+            false
+        }
+    }
+}
+
+/// with --check reads the painted pixels back and reports the ones that differ
+/// the pixels that failed are written to --error-log if given
+async fn check<C: Connection>(
+    args: &Arguments,
+    stream: &mut C,
+    painted: &[Pixel],
+) -> Result<(), Box<dyn Error>> {
+    if !args.check {
+        return Ok(());
+    }
+    let coords: Vec<(u32, u32)> = painted.iter().map(|pxl| (pxl.x, pxl.y)).collect();
+    let colors = read_pixels(stream, &coords, args.pipeline).await?;
+
+    let mut failed = 0;
+    let mut log = String::from("x,y,color,response\n");
+    for pxl in painted {
+        let response = &colors[&(pxl.x, pxl.y)];
+        // the server answers in its channel order
+        if same_color(response, &args.channel_order.arrange_hex(&pxl.color)) {
+            continue;
+        }
+        failed += 1;
+        match args.error_log {
+            Some(_) => log.push_str(&format!("{},{},{},{response}\n", pxl.x, pxl.y, pxl.color)),
+            None => println!(
+                "WARN: {} {} should be {} but is {response}",
+                pxl.x, pxl.y, pxl.color
+            ),
+        }
+    }
+    if let Some(path) = &args.error_log {
+        tokio::fs::write(path, log).await?;
+    }
+    println!("{failed} of {} pixels failed the check", painted.len());
+    Ok(())
+}
+
+/// prints the commands of every worker or, with --dryrun-stats, an estimation
+/// of the traffic of a single pass
+async fn dry_run(args: &Arguments, tasks: &[Vec<Vec<u8>>]) -> Result<(), Box<dyn Error>> {
+    if !args.dryrun_stats {
+        if let Some(split) = &args.split_output {
+            return split_output(&split[0], &split[1], tasks).await;
+        }
+        let mut stdout = tokio::io::stdout();
+        for task in tasks {
+            let mut progress = Progress::default();
+            paint(
+                &mut stdout,
+                task,
+                false,
+                &mut Throttle::default(),
+                &mut progress,
+            )
+            .await?;
+        }
+        return Ok(());
+    }
+
+    let Forecast {
+        commands,
+        bytes,
+        writes,
+    } = Forecast::new(tasks);
+    let seconds = bytes as f64 / args.rate.max(1) as f64;
+    println!("commands: {commands}");
+    println!("bytes: {bytes}");
+    println!("writes: {writes}");
+    println!("estimated duration: {seconds:.3}s at {} bytes/s", args.rate);
+
+    // standard commands with gray or opaque colors can be shorter
+    let colors = color_counts(tasks.iter().flatten());
+    let savings = Savings::estimate(&colors);
+    let share = |saved: usize| saved as f64 * 100.0 / bytes.max(1) as f64;
+    let estimates = [
+        ("gray as ww", savings.gray),
+        ("opaque colors without alpha", savings.alpha),
+        (
+            &format!("a palette of {PALETTE_SIZE} colors with --short-colors"),
+            savings.palette,
+        ),
+    ];
+    for (mode, saved) in estimates {
+        println!("saved by {mode}: {saved} bytes ({:.1}%)", share(saved));
+    }
+    match savings.best() {
+        Some((flags, saved)) => println!(
+            "suggestion: {flags} saves {saved} bytes ({:.1}%)",
+            share(saved)
+        ),
+        None => println!("suggestion: none, no mode makes the colors shorter"),
+    }
+
+    // the palette shows if fewer colors would save bandwidth
+    print_top_colors(args, &colors, commands);
+    Ok(())
+}
+
+/// what --dryrun-stats expects to send
+#[derive(Debug, PartialEq, Eq)]
+struct Forecast {
+    commands: usize,
+    bytes: usize,
+    writes: usize,
+}
+
+impl Forecast {
+    fn new(tasks: &[Vec<Vec<u8>>]) -> Self {
+        Self {
+            commands: tasks
+                .iter()
+                .flatten()
+                .map(|cmd| protocol::split_commands(cmd).count())
+                .sum(),
+            bytes: tasks.iter().flatten().map(|cmd| cmd.len()).sum(),
+            writes: tasks.iter().map(|task| task.len()).sum(),
+        }
+    }
+}
+
+/// counts how often every color is painted by the commands
+fn color_counts<'a>(
+    batches: impl Iterator<Item = &'a Vec<u8>>,
+) -> std::collections::HashMap<String, usize> {
+    batches
+        .flat_map(|cmd| protocol::split_commands(cmd))
+        .filter_map(protocol::color)
+        .counts()
+}
+
+/// the most used colors, colors that are used equally often are sorted by name
+fn top_colors(
+    colors: &std::collections::HashMap<String, usize>,
+    count: usize,
+) -> Vec<(&String, &usize)> {
+    colors
+        .iter()
+        .sorted_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)))
+        .take(count)
+        .collect()
+}
+
+/// prints the number of colors and the --top-colors most used ones
+fn print_top_colors(
+    args: &Arguments,
+    colors: &std::collections::HashMap<String, usize>,
+    commands: usize,
+) {
+    println!("colors: {}", colors.len());
+    for (color, count) in top_colors(colors, args.top_colors) {
+        println!(
+            "  {color}: {count} ({:.1}%)",
+            *count as f64 * 100.0 / commands.max(1) as f64
+        );
+    }
+}
+
+/// with --output-commands-stats prints the most used colors before painting
+fn output_commands_stats<'a>(args: &Arguments, batches: impl Iterator<Item = &'a Vec<u8>> + Clone) {
+    if !args.output_commands_stats {
+        return;
+    }
+    let commands = batches
+        .clone()
+        .map(|cmd| protocol::split_commands(cmd).count())
+        .sum();
+    print_top_colors(args, &color_counts(batches), commands);
+}
+
+/// the bytes shorter colors would save for the standard commands
+#[derive(Debug, Default, PartialEq)]
+struct Savings {
+    /// gray colors as ww with --short-colors
+    gray: usize,
+    /// opaque colors without alpha with --short-colors
+    alpha: usize,
+    /// the colors mapped to a palette extracted from them with --palette-from,
+    /// then sent with --short-colors
+    palette: usize,
+}
+
+impl Savings {
+    fn estimate(colors: &std::collections::HashMap<String, usize>) -> Self {
+        let mut savings = Self::default();
+        // colors with 16 bit per channel are sent as they are
+        let colors: Vec<(&String, usize)> = colors
+            .iter()
+            .filter(|(color, _)| color.len() <= 8)
+            .map(|(color, count)| (color, *count))
+            .collect();
+        for (color, count) in &colors {
+            let alpha = match color.len() {
+                8 if color[6..].eq_ignore_ascii_case("ff") => 2,
+                _ => 0,
+            };
+            savings.alpha += alpha * count;
+            savings.gray += (color.len() - alpha - shorten(color).len()) * count;
+        }
+
+        let mut counted = std::collections::HashMap::new();
+        for (color, count) in &colors {
+            let [r, g, b, _] = rgba(color);
+            *counted.entry([r, g, b]).or_default() += *count as u64;
+        }
+        let palette = palette::extract_counted(counted, PALETTE_SIZE as usize);
+        for (color, count) in &colors {
+            let [r, g, b, _] = rgba(color);
+            let [r, g, b] = *palette::nearest(&palette, &[r, g, b]);
+            let mapped = shorten(&format!("{r:02x}{g:02x}{b:02x}"));
+            savings.palette += color.len().saturating_sub(mapped.len()) * count;
+        }
+        savings
+    }
+
+    /// the flags that save the most bytes, a palette only if it saves more than
+    /// --short-colors because it changes the colors
+    fn best(&self) -> Option<(&'static str, usize)> {
+        let short = self.gray + self.alpha;
+        match self.palette > short {
+            true => Some((
+                "--palette-from with the image itself and --short-colors",
+                self.palette,
+            )),
+            false if short > 0 => Some(("--short-colors", short)),
+            false => None,
+        }
+    }
+}
+
+/// writes the commands into `n` files, every file has the same number of commands
+/// except for OFFSET, every file repeats the OFFSET its commands depend on
+async fn split_output(prefix: &str, n: &str, tasks: &[Vec<Vec<u8>>]) -> Result<(), Box<dyn Error>> {
+    let n: usize = match n.parse() {
+        Ok(n) if n > 0 => n,
+        _ => return Err(format!("invalid number of files for --split-output: {n}").into()),
+    };
+
+    let cmds: Vec<u8> = tasks.iter().flatten().flatten().copied().collect();
+    let (lines, offsets): (Vec<&[u8]>, Vec<Option<&[u8]>>) = protocol::split_commands(&cmds)
+        .scan(None, |offset, line| {
+            if line.starts_with(b"OFFSET ") {
+                *offset = Some(line);
+                return Some(None);
+            }
+            Some(Some((line, *offset)))
+        })
+        .flatten()
+        .unzip();
+
+    for i in 0..n {
+        let (start, end) = (lines.len() * i / n, lines.len() * (i + 1) / n);
+        let mut file = vec![];
+        let mut offset = None;
+        for (line, line_offset) in lines[start..end].iter().zip(&offsets[start..end]) {
+            if line_offset.is_some() && *line_offset != offset {
+                offset = *line_offset;
+                file.extend_from_slice(line_offset.unwrap());
+            }
+            file.extend_from_slice(line);
+        }
+        tokio::fs::write(format!("{prefix}{i}"), file).await?;
+    }
+    Ok(())
+}
+
+/// paints the lines from stdin as scrolling text until stdin is closed
+async fn mirror_stdin(args: &Arguments, console: &Console) -> Result<(), Box<dyn Error>> {
+    mirror(args, console, tokio::io::stdin()).await
+}
+
+/// paints the lines of the input as scrolling text until the input is closed
+async fn mirror<R>(args: &Arguments, console: &Console, input: R) -> Result<(), Box<dyn Error>>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    let canvas_limit = size(args).await?;
+    let mut stream = sink::connect_recorded(args).await?;
+
+    // reading stdin waits while the channel is full, so lines that arrive
+    // faster than they can be painted stay in the pipe instead of piling up
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<String>(64);
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(input).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if sender.send(line).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    fn push(buffer: &mut VecDeque<String>, console: &Console, line: String) {
+        let chars: Vec<char> = line.chars().collect();
+        // empty lines still take up a row
+        let wrapped: Vec<String> = if chars.is_empty() {
+            vec![String::new()]
+        } else {
+            chars
+                .chunks(console.columns.max(1) as usize)
+                .map(|c| c.iter().collect())
+                .collect()
+        };
+        for line in wrapped {
+            if buffer.len() == console.rows {
+                buffer.pop_front();
+            }
+            buffer.push_back(line);
+        }
+    }
+
+    let mut buffer: VecDeque<String> = VecDeque::with_capacity(console.rows);
+    while let Some(line) = receiver.recv().await {
+        // take everything that arrived while painting and paint only the latest state
+        push(&mut buffer, console, line);
+        while let Ok(line) = receiver.try_recv() {
+            push(&mut buffer, console, line);
+        }
+
+        let mut lines: Vec<String> = buffer.iter().cloned().collect();
+        lines.resize(console.rows, String::new());
+        let mut cmds = vec![];
+        text::render(&lines, console.columns, |dx, dy, set| {
+            let (x, y) = (console.x + dx, console.y + dy);
+            if x < canvas_limit.0 && y < canvas_limit.1 {
+                let color = if set {
+                    console.color.clone()
+                } else {
+                    console.background.clone()
+                };
+                cmds.extend(Pixel { x, y, color }.encode(args));
+            }
+        });
+        stream.write_all(&cmds).await?;
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+/// prints the HELP command to the pixelflut server
+async fn howto(args: &Arguments) -> Result<(), Box<dyn Error>> {
+    let mut stream = sink::connect(args).await?;
+
+    // send HELP
+    stream.write_all(b"HELP\n").await?;
+
+    // receive
+    let mut buffer = Vec::with_capacity(256);
+    stream.read_until(b'\n', &mut buffer).await?;
+    let help = protocol::parse_help(&buffer)?;
+    println!("{help:?}");
+    Ok(())
+}
+
+/// checks if the server knows the OFFSET command
+/// the server has to list OFFSET in its HELP and not answer to an OFFSET,
+/// because a successful OFFSET has no response
+async fn offset_supported(args: &Arguments) -> bool {
+    let probe = async {
+        let mut stream = sink::connect(args).await?;
+        let help = request(&mut stream, "HELP").await?;
+        let rejection = request(&mut stream, "OFFSET 0 0").await?;
+        Ok::<_, Box<dyn Error>>(protocol::help_mentions(&help, "OFFSET") && rejection.is_empty())
+    };
+    let timeout = std::time::Duration::from_secs(2);
+    let supported = matches!(tokio::time::timeout(timeout, probe).await, Ok(Ok(true)));
+    if !supported {
+        println!("WARN: server does not support OFFSET, adding the offset to the coordinates");
+    }
+    supported
+}
+
+/// with --protocol extended falls back to standard commands if the server
+/// doesn't support them, without --protocol the extended commands are chosen
+/// if the server reports a color depth of more than 8 bit per channel
+async fn choose_protocol(args: &mut Arguments, chosen: bool) {
+    // without a server, or a canvas size without asking it, there is nothing to ask
+    let overridden = args.canvas_x.is_some() && args.canvas_y.is_some();
+    // these send no commands that paint
+    let paints = !matches!(
+        args.command,
+        Command::Howto
+            | Command::Size
+            | Command::LoadTest(_)
+            | Command::Replay(_)
+            | Command::Undo(_)
+    );
+    if args.is_dry_run() || (!chosen && (overridden || !paints)) {
+        return;
+    }
+    match args.protocol {
+        Protocol::Extended if !extended_supported(args).await => {
+            println!("WARN: server does not support 16 bit colors, using standard commands");
+            args.protocol = Protocol::Standard;
+        }
+        Protocol::Standard if !chosen && deep_canvas(args).await => {
+            println!("the canvas has 16 bit colors, using extended commands");
+            args.protocol = Protocol::Extended;
+        }
+        _ => {}
+    }
+}
+
+/// checks if the server accepts colors with 16 bit per channel
+/// the server has to list the format rrrrggggbbbb in its HELP or report the
+/// depth in its SIZE
+async fn extended_supported(args: &Arguments) -> bool {
+    let probe = async {
+        let mut stream = sink::connect(args).await?;
+        let help = request(&mut stream, "HELP").await?;
+        if protocol::help_mentions(&help, "rrrrggggbbbb") {
+            return Ok::<_, Box<dyn Error>>(true);
+        }
+        deep_colors(&mut stream).await
+    };
+    let timeout = std::time::Duration::from_secs(2);
+    matches!(tokio::time::timeout(timeout, probe).await, Ok(Ok(true)))
+}
+
+/// checks if the canvas of the server has more than 8 bit per channel
+async fn deep_canvas(args: &Arguments) -> bool {
+    let probe = async {
+        let mut stream = sink::connect(args).await?;
+        deep_colors(&mut stream).await
+    };
+    let timeout = std::time::Duration::from_secs(2);
+    matches!(tokio::time::timeout(timeout, probe).await, Ok(Ok(true)))
+}
+
+/// checks if the depth in the SIZE of the server is more than 8 bit per
+/// channel, like 48 for rgb or 64 for rgba with 16 bit each
+async fn deep_colors<C: Connection>(stream: &mut C) -> Result<bool, Box<dyn Error>> {
+    stream.write_all(b"SIZE\n").await?;
+    let size = read_response(stream, "SIZE").await?;
+    let (_, _, depth) = protocol::parse_size(&size)?;
+    Ok(depth.is_some_and(|depth| depth > 32))
+}
+
+/// sends a command followed by SIZE, everything before the SIZE response
+/// belongs to the command, so commands without response return nothing
+async fn request<C: Connection>(stream: &mut C, cmd: &str) -> Result<String, Box<dyn Error>> {
+    stream
+        .write_all(format!("{cmd}\nSIZE\n").as_bytes())
+        .await?;
+    let mut response = String::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err("connection closed".into());
+        }
+        if line.starts_with("SIZE ") {
+            return Ok(response);
+        }
+        response.push_str(&line);
+    }
+}
+
+/// query the size of the pixelflut server canvas
+async fn size(args: &Arguments) -> Result<(u32, u32), Box<dyn Error>> {
+    if let (Some(x), Some(y)) = (args.canvas_x, args.canvas_y) {
+        return Ok((x, y));
+    }
+    query_size(args).await
+}
+
+/// makes sure the server answers before starting expensive work
+async fn preconnect_check(args: &Arguments) -> Result<(), Box<dyn Error>> {
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    match tokio::time::timeout(TIMEOUT, query_size(args)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("server {} is not usable: {e}", args.domain).into()),
+        Err(_) => Err(format!(
+            "server {} did not answer SIZE within {}s",
+            args.domain,
+            TIMEOUT.as_secs()
+        )
+        .into()),
+    }
+}
+
+/// asks the server for the size of its canvas
+async fn query_size(args: &Arguments) -> Result<(u32, u32), Box<dyn Error>> {
+    let mut stream = sink::connect(args).await?;
+
+    // send SIZE
+    stream.write_all(b"SIZE\n").await?;
+
+    // receive
+    let buffer = read_response(&mut stream, "SIZE").await?;
+
+    let (width, height, _) = protocol::parse_size(&buffer)?;
+    Ok((width, height))
+}
+
+/// reads the line that answers a command, it starts with the name of the command
+/// lines before it are skipped, because some servers greet with a banner
+/// that would be taken as the response otherwise
+async fn read_response<C: Connection>(
+    stream: &mut C,
+    command: &str,
+) -> Result<String, Box<dyn Error>> {
+    const MAX_SKIPPED_LINES: usize = 16;
+
+    for _ in 0..=MAX_SKIPPED_LINES {
+        let mut line = String::with_capacity(32);
+        if stream.read_line(&mut line).await? == 0 {
+            let msg = format!("connection closed before the response to {command}");
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, msg).into());
+        }
+        if line.starts_with(command) {
+            return Ok(line);
+        }
+    }
+    Err(format!("server sent no response to {command}").into())
+}
+
+/// stores the current colors of the given pixels in the history file
+/// the history consists of PX commands, so sending it restores the colors
+async fn record_history(
+    args: &Arguments,
+    pixels: impl Iterator<Item = (u32, u32)>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(path) = &args.history else {
+        return Ok(());
+    };
+
+    let mut pixels: Vec<(u32, u32)> = pixels.take(args.history_limit + 1).collect();
+    if pixels.len() > args.history_limit {
+        println!(
+            "WARN: history is limited to {} pixels, the rest is not stored",
+            args.history_limit
+        );
+        pixels.truncate(args.history_limit);
+    }
+
+    let mut stream = sink::connect(args).await?;
+    let colors = read_pixels(&mut stream, &pixels, args.pipeline).await?;
+    let mut history = String::new();
+    for (x, y) in pixels {
+        let color = colors[&(x, y)].clone();
+        history.push_str(&Pixel { x, y, color }.to_cmd());
+    }
+    tokio::fs::write(path, history).await?;
+    Ok(())
+}
+
+/// sends a recording on one connection
+/// writes of a timed recording are sent at the same distance as recorded
+async fn play(args: &Arguments, replay: &Replay) -> Result<(), Box<dyn Error>> {
+    let recording = tokio::fs::read(&replay.path).await?;
+    let mut stream = sink::connect(args).await?;
+    if !recording.starts_with(b"@") {
+        stream.write_all(&recording).await?;
+        stream.flush().await?;
+        return Ok(());
+    }
+
+    let writes = parse_recording(&recording)
+        .ok_or_else(|| format!("invalid recording {}", replay.path.display()))?;
+    let first = writes.first().map_or(0, |(time, _)| *time);
+    let start = tokio::time::Instant::now();
+    for (time, bytes) in writes {
+        let due = start + std::time::Duration::from_micros(time.saturating_sub(first));
+        if due > tokio::time::Instant::now() {
+            // everything before the pause should be visible while waiting
+            stream.flush().await?;
+            tokio::time::sleep_until(due).await;
+        }
+        stream.write_all(bytes).await?;
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+/// splits a timed recording into its writes and the microseconds they were sent at
+fn parse_recording(mut recording: &[u8]) -> Option<Vec<(u64, &[u8])>> {
+    let mut writes = vec![];
+    while !recording.is_empty() {
+        let end = recording.iter().position(|b| *b == b'\n')?;
+        let header = std::str::from_utf8(&recording[..end]).ok()?;
+        let (time, len) = header.strip_prefix('@')?.split_once(' ')?;
+        let (time, len): (u64, usize) = (time.parse().ok()?, len.parse().ok()?);
+        let bytes = recording.get(end + 1..end + 1 + len)?;
+        writes.push((time, bytes));
+        recording = &recording[end + 1 + len..];
+    }
+    Some(writes)
+}
+
+/// restores the colors stored in a history file
+async fn restore(args: &Arguments, undo: &Undo) -> Result<(), Box<dyn Error>> {
+    let history = tokio::fs::read_to_string(&undo.path).await?;
+    if let Some(line) = history.lines().find(|line| !line.starts_with("PX ")) {
+        return Err(format!("invalid history entry: {line:?}").into());
+    }
+
+    let mut stream = sink::connect_recorded(args).await?;
+    stream.write_all(history.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// set once the warning about pixels that compact commands can't reach was shown
+static COMPACT_DROPPED: AtomicBool = AtomicBool::new(false);
+
+impl Pixel {
+    pub fn to_cmd(&self) -> String {
+        format!("PX {} {} {}\n", self.x, self.y, self.color)
+    }
+
+    /// the command in the syntax of the protocol with the channels in the
+    /// order of the server
+    pub fn encode(&self, args: &Arguments) -> Vec<u8> {
+        let color = args.channel_order.arrange_hex(&self.color);
+        match args.protocol {
+            Protocol::Standard if args.short_colors => {
+                format!("PX {} {} {}\n", self.x, self.y, shorten(&color)).into_bytes()
+            }
+            Protocol::Standard => format!("PX {} {} {color}\n", self.x, self.y).into_bytes(),
+            Protocol::Compact => {
+                let (Ok(x), Ok(y)) = (u16::try_from(self.x), u16::try_from(self.y)) else {
+                    // a wrapped coordinate would paint the pixel somewhere else
+                    if !COMPACT_DROPPED.swap(true, Ordering::Relaxed) {
+                        println!("WARN: compact commands can't reach pixels after 65535, they are dropped");
+                    }
+                    return vec![];
+                };
+                let mut cmd = Vec::with_capacity(protocol::PB_LEN);
+                cmd.extend_from_slice(b"PB");
+                cmd.extend_from_slice(&x.to_le_bytes());
+                cmd.extend_from_slice(&y.to_le_bytes());
+                cmd.extend_from_slice(&rgba(&color));
+                cmd
+            }
+            Protocol::Extended => {
+                let color = match color.len() {
+                    12 | 16 => color,
+                    len => {
+                        let channels = if len == 8 { 4 } else { 3 };
+                        rgba(&color)[..channels]
+                            .iter()
+                            .map(|channel| format!("{:04x}", *channel as u16 * 257))
+                            .collect()
+                    }
+                };
+                format!("PX {} {} {color}\n", self.x, self.y).into_bytes()
+            }
+        }
+    }
+}
+
+/// the shortest hex of a color, gray as ww and opaque colors without alpha
+/// colors with 16 bit per channel are kept
+fn shorten(color: &str) -> String {
+    let color = match color.len() {
+        8 if color[6..].eq_ignore_ascii_case("ff") => &color[..6],
+        _ => color,
+    };
+    let gray = color.len() == 6
+        && color[0..2].eq_ignore_ascii_case(&color[2..4])
+        && color[2..4].eq_ignore_ascii_case(&color[4..6]);
+    if gray {
+        color[..2].to_string()
+    } else {
+        color.to_string()
+    }
+}
+
+/// compares the color a server answered with the color that was painted
+/// servers answer without alpha
+fn same_color(response: &str, painted: &str) -> bool {
+    rgba(response)[..3] == rgba(painted)[..3]
+}
+
+/// the channels of a color in hex, colors without alpha are opaque
+/// colors with 16 bit per channel are reduced to 8 bit
+/// invalid channels are 0, see parse_color
+fn rgba(color: &str) -> [u8; 4] {
+    let width = if color.len() >= 12 { 4 } else { 2 };
+    let channel = |i: usize| {
+        color
+            .get(i * width..i * width + 2)
+            .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+            .unwrap_or_default()
+    };
+    match color.len() / width {
+        1 => [channel(0), channel(0), channel(0), 255],
+        3 => [channel(0), channel(1), channel(2), 255],
+        _ => [channel(0), channel(1), channel(2), channel(3)],
+    }
+}
+
+/// bytes given as text with escapes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bytes(Vec<u8>);
+
+/// replaces the escapes \n, \r, \t, \\ and \xHH
+fn parse_bytes(input: &str) -> Result<Bytes, String> {
+    let mut bytes = vec![];
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                // from_str_radix would also take a single digit or a sign
+                let byte = u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+                    .ok_or_else(|| format!("\\x needs two hex digits, got {hex:?}"))?;
+                bytes.push(byte);
+            }
+            Some(c) => return Err(format!("unknown escape \\{c}")),
+            None => return Err(String::from("escape at the end")),
+        }
+    }
+    Ok(Bytes(bytes))
+}
+
+/// accepts colors in hex as gray (ww), rgb (rrggbb) or rgba (rrggbbaa)
+fn parse_color(input: &str) -> Result<String, String> {
+    if matches!(input.len(), 2 | 6 | 8) && input.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(input.to_string())
+    } else {
+        Err(String::from("color has to be 2, 6 or 8 hex digits"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::net::TcpListener;
+
+    /// what a pixelflut server of the tests received
+    #[derive(Debug, Default)]
+    struct Canvas {
+        size: (u32, u32),
+        pixels: HashMap<(u32, u32), String>,
+        /// every line in the order it was processed
+        received: Vec<String>,
+        /// when every line of received arrived
+        arrived: Vec<std::time::Instant>,
+        connections: usize,
+        /// the pixels every connection painted, by the number of the
+        /// connection counted from 1
+        painted: HashMap<usize, usize>,
+        /// resets the first connection that painted this many pixels
+        resets_after: Option<usize>,
+        /// resets every connection after resets_after, not only the first
+        keeps_resetting: bool,
+        /// answers OFFSET with an error like servers without OFFSET
+        rejects_offset: bool,
+        /// the greeting sent after connecting
+        banner: Option<String>,
+        /// pixels that keep their color when they are painted
+        rejected: HashSet<(u32, u32)>,
+        /// closes the connection after it answered a query for a pixel
+        closes_after_answer: bool,
+        /// the color depth in bits that SIZE answers after the size
+        depth: Option<u32>,
+        /// waits this long after every line like a slow server
+        read_delay: Option<Duration>,
+    }
+
+    impl Canvas {
+        fn color(&self, x: u32, y: u32) -> &str {
+            self.pixels.get(&(x, y)).map_or("000000", String::as_str)
+        }
+    }
+
+    /// starts a pixelflut server with a canvas of this size on a free port
+    async fn serve(size: (u32, u32)) -> (String, Arc<Mutex<Canvas>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let canvas = Arc::new(Mutex::new(Canvas {
+            size,
+            ..Canvas::default()
+        }));
+        let shared = canvas.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let (connection, banner, read_delay) = {
+                    let mut canvas = shared.lock().unwrap();
+                    canvas.connections += 1;
+                    (canvas.connections, canvas.banner.clone(), canvas.read_delay)
+                };
+                let canvas = shared.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = socket.into_split();
+                    if let Some(banner) = banner {
+                        let _ = write.write_all(banner.as_bytes()).await;
+                    }
+                    let mut lines = BufReader::new(read).lines();
+                    let mut offset = (0, 0);
+                    let mut resets = false;
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let mut closes = false;
+                        let response = {
+                            let mut canvas = canvas.lock().unwrap();
+                            canvas.received.push(line.clone());
+                            canvas.arrived.push(std::time::Instant::now());
+                            let fields: Vec<&str> = line.split_whitespace().collect();
+                            let number = |i: usize| fields[i].parse::<u32>().unwrap_or(0);
+                            match fields[..] {
+                                ["SIZE"] => {
+                                    let (width, height) = canvas.size;
+                                    Some(match canvas.depth {
+                                        Some(depth) => format!("SIZE {width} {height} {depth}\n"),
+                                        None => format!("SIZE {width} {height}\n"),
+                                    })
+                                }
+                                ["HELP"] => Some("HELP PX x y rrggbb, SIZE, OFFSET x y\n".into()),
+                                ["OFFSET", _, _] if canvas.rejects_offset => {
+                                    Some("ERROR unknown command\n".into())
+                                }
+                                ["OFFSET", _, _] => {
+                                    offset = (number(1), number(2));
+                                    None
+                                }
+                                ["PX", _, _] => {
+                                    let (x, y) = (number(1) + offset.0, number(2) + offset.1);
+                                    closes = canvas.closes_after_answer;
+                                    Some(format!("PX {x} {y} {}\n", canvas.color(x, y)))
+                                }
+                                ["PX", _, _, color] => {
+                                    let (x, y) = (number(1) + offset.0, number(2) + offset.1);
+                                    *canvas.painted.entry(connection).or_default() += 1;
+                                    if canvas.resets_after
+                                        == canvas.painted.get(&connection).copied()
+                                    {
+                                        if !canvas.keeps_resetting {
+                                            canvas.resets_after = None;
+                                        }
+                                        resets = true;
+                                    }
+                                    if !canvas.rejected.contains(&(x, y)) {
+                                        canvas
+                                            .pixels
+                                            .insert((x, y), color[..6.min(color.len())].into());
+                                    }
+                                    None
+                                }
+                                _ => None,
+                            }
+                        };
+                        if let Some(response) = response {
+                            if write.write_all(response.as_bytes()).await.is_err() {
+                                break;
+                            }
+                        }
+                        if closes || resets {
+                            break;
+                        }
+                        if let Some(delay) = read_delay {
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                    if resets {
+                        let socket = lines.into_inner().into_inner().reunite(write).unwrap();
+                        socket.set_linger(Some(Duration::ZERO)).unwrap();
+                    }
+                });
+            }
+        });
+        (address, canvas)
+    }
+
+    /// the connections that were not opened to query the size of the canvas
+    fn painting(canvas: &Canvas) -> usize {
+        let queries = canvas
+            .received
+            .iter()
+            .filter(|line| *line == "SIZE")
+            .count();
+        canvas.connections - queries
+    }
+
+    /// waits until the server processed what the clients sent
+    async fn settled(canvas: &Mutex<Canvas>, done: impl Fn(&Canvas) -> bool) {
+        for _ in 0..500 {
+            if done(&canvas.lock().unwrap()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let canvas = canvas.lock().unwrap();
+        let last = &canvas.received[canvas.received.len().saturating_sub(10)..];
+        panic!(
+            "server did not get there: {:?}, last received {last:?}",
+            canvas.pixels
+        );
+    }
+
+    /// the arguments of a run like on the command line
+    fn parse(line: &str) -> Arguments {
+        Arguments::try_parse_from(["pxlclnt"].into_iter().chain(line.split_whitespace())).unwrap()
+    }
+
+    /// a file in the temporary directory that is unique for the test
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pxlclnt-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// a png with the colors of every pixel
+    fn png(width: u32, height: u32, color: impl Fn(u32, u32) -> [u8; 3]) -> Vec<u8> {
+        let image = image::RgbImage::from_fn(width, height, |x, y| image::Rgb(color(x, y)));
+        let mut png = std::io::Cursor::new(vec![]);
+        image::DynamicImage::ImageRgb8(image)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    /// an 8x8 png with a different color for every pixel
+    fn gradient(name: &str) -> PathBuf {
+        let path = temp(name);
+        std::fs::write(&path, png(8, 8, |x, y| [x as u8 * 32, y as u8 * 32, 0])).unwrap();
+        path
+    }
+
+    /// the files --split-output writes for a run without server, of a canvas of 64x64
+    async fn split(name: &str, flags: &str, files: usize) -> Vec<Vec<u8>> {
+        let prefix = temp(name);
+        let args = parse(&format!(
+            "-d localhost --canvas-x 64 --canvas-y 64 --split-output {} {files} {flags}",
+            prefix.display()
+        ));
+        match &args.command {
+            Command::Image(img) => {
+                picture::run(&args, img).await.unwrap();
+            }
+            Command::Rect(painted) => rect(&args, painted).await.unwrap(),
+            _ => unreachable!(),
+        }
+        (0..files)
+            .map(|i| {
+                let path = PathBuf::from(format!("{}{i}", prefix.display()));
+                let file = std::fs::read(&path).unwrap();
+                std::fs::remove_file(path).unwrap();
+                file
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn undo_restores_the_colors_a_rect_overwrote() {
+        let (address, canvas) = serve((8, 8)).await;
+        canvas
+            .lock()
+            .unwrap()
+            .pixels
+            .insert((1, 1), "123456".into());
+        let history = temp("history");
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 1 --history {} rect 0 0 2 2 ff0000",
+            history.display()
+        ));
+        let Command::Rect(painted) = &args.command else {
+            unreachable!()
+        };
+        rect(&args, painted).await.unwrap();
+        settled(&canvas, |canvas| {
+            canvas.color(0, 0) == "ff0000" && canvas.color(1, 1) == "ff0000"
+        })
+        .await;
+
+        let undo = Undo { path: history };
+        restore(&args, &undo).await.unwrap();
+        settled(&canvas, |canvas| {
+            canvas.color(0, 0) == "000000" && canvas.color(1, 1) == "123456"
+        })
+        .await;
+        assert_eq!(canvas.lock().unwrap().pixels.len(), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paints_a_base64_data_uri() {
+        use base64::Engine;
+
+        let (address, canvas) = serve((8, 8)).await;
+        let png = png(2, 1, |x, _| [[255, 0, 0], [0, 0, 255]][x as usize]);
+        let uri = base64::engine::general_purpose::STANDARD.encode(png);
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 10 image 3 4 data:image/png;base64,{uri}"
+        ));
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        picture::run(&args, img).await.unwrap();
+        settled(&canvas, |canvas| {
+            canvas.color(3, 4) == "ff0000" && canvas.color(4, 4) == "0000ff"
+        })
+        .await;
+        assert_eq!(canvas.lock().unwrap().pixels.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn mirrors_piped_lines_as_text() {
+        let (address, canvas) = serve((64, 64)).await;
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 1 console 2 3 --columns 4 --rows 2"
+        ));
+        let Command::Console(console) = &args.command else {
+            unreachable!()
+        };
+        let (mut pipe, input) = tokio::io::duplex(64);
+        pipe.write_all(b"Hi\n").await.unwrap();
+        drop(pipe);
+        mirror(&args, console, input).await.unwrap();
+
+        let mut glyphs = vec![];
+        text::render(&["Hi".into(), String::new()], 4, |x, y, set| {
+            glyphs.push((2 + x, 3 + y, set))
+        });
+        settled(&canvas, |canvas| canvas.pixels.len() == glyphs.len()).await;
+        let canvas = canvas.lock().unwrap();
+        assert!(glyphs.iter().any(|(_, _, set)| *set));
+        for (x, y, set) in glyphs {
+            let color = if set { "ffffff" } else { "000000" };
+            assert_eq!(canvas.color(x, y), color, "pixel {x} {y}");
+        }
+    }
+
+    #[tokio::test]
+    async fn forecast_matches_the_bytes_of_a_paint() {
+        let (address, canvas) = serve((8, 8)).await;
+        let args = parse(&format!("-d {address} -t 1 -s 1 rect 1 2 4 6 ff00ff"));
+        let Command::Rect(painted) = &args.command else {
+            unreachable!()
+        };
+        let cmds: Vec<Vec<u8>> = rect_cmds(painted, &args, &mut rng(&args)).collect();
+        let forecast = Forecast::new(&[cmds]);
+        assert_eq!(forecast.commands, 12);
+
+        rect(&args, painted).await.unwrap();
+        settled(&canvas, |canvas| canvas.pixels.len() == 12).await;
+        let sent: usize = canvas
+            .lock()
+            .unwrap()
+            .received
+            .iter()
+            .filter(|line| line.split_whitespace().count() == 4)
+            .map(|line| line.len() + 1)
+            .sum();
+        assert_eq!(forecast.bytes, sent);
+    }
+
+    #[tokio::test]
+    async fn paints_a_rect_into_memory() {
+        let args = parse("-d localhost -t 1 -s 2 rect 0 1 2 2 abcdef");
+        let Command::Rect(painted) = &args.command else {
+            unreachable!()
+        };
+        let cmds: Vec<Vec<u8>> = rect_cmds(painted, &args, &mut rng(&args)).collect();
+        let mut sink = vec![];
+        let mut progress = Progress::default();
+        paint(
+            &mut sink,
+            &cmds,
+            false,
+            &mut Throttle::default(),
+            &mut progress,
+        )
+        .await
+        .unwrap();
+        assert_eq!(sink, b"PX 0 1 abcdef\nPX 1 1 abcdef\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn adds_the_offset_if_the_server_rejects_offset() {
+        let (address, canvas) = serve((8, 8)).await;
+        canvas.lock().unwrap().rejects_offset = true;
+        let path = temp("offset.png");
+        std::fs::write(&path, png(2, 2, |x, y| [x as u8 * 255, y as u8 * 255, 0])).unwrap();
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 10 --offset image 3 4 {}",
+            path.display()
+        ));
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        picture::run(&args, img).await.unwrap();
+        settled(&canvas, |canvas| canvas.pixels.len() == 4).await;
+        let canvas = canvas.lock().unwrap();
+        assert_eq!(canvas.color(3, 4), "000000");
+        assert_eq!(canvas.color(4, 4), "ff0000");
+        assert_eq!(canvas.color(3, 5), "00ff00");
+        assert_eq!(canvas.color(4, 5), "ffff00");
+        assert!(!canvas.received.iter().any(|line| line == "OFFSET 3 4"));
+    }
+
+    #[tokio::test]
+    async fn skips_the_banner_of_the_server() {
+        let (address, canvas) = serve((8, 6)).await;
+        canvas.lock().unwrap().banner = Some("welcome to pixelflut\n".into());
+        for flags in ["", "--expect-banner"] {
+            let args = parse(&format!(
+                "-d {address} -t 1 -s 1 {flags} rect 0 0 1 1 ff0000"
+            ));
+            assert_eq!(size(&args).await.unwrap(), (8, 6), "{flags}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deterministic_shuffles_are_identical() {
+        let path = gradient("shuffle.png");
+        let image = format!("image 0 0 {} --shuffle", path.display());
+        let flags = format!("-t 2 -s 4 --deterministic {image}");
+        let first = split("shuffle-first", &flags, 1).await;
+        let second = split("shuffle-second", &flags, 1).await;
+        assert_eq!(first, second);
+
+        let seeded = split("shuffle-seeded", &format!("-t 2 -s 4 --seed 7 {image}"), 1).await;
+        assert_ne!(first, seeded);
+        let mut shuffled: Vec<&[u8]> = protocol::split_commands(&first[0]).collect();
+        let mut seeded: Vec<&[u8]> = protocol::split_commands(&seeded[0]).collect();
+        shuffled.sort_unstable();
+        seeded.sort_unstable();
+        assert_eq!(shuffled, seeded);
+        assert_eq!(shuffled.len(), 64);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uses_at_most_a_worker_per_pixel() {
+        let (address, canvas) = serve((16, 16)).await;
+        let path = temp("threads.png");
+        std::fs::write(&path, png(10, 1, |x, _| [x as u8 + 1, 0, 0])).unwrap();
+        let args = parse(&format!(
+            "-d {address} -t 100 -s 1 image 0 0 {}",
+            path.display()
+        ));
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        picture::run(&args, img).await.unwrap();
+        settled(&canvas, |canvas| canvas.pixels.len() == 10).await;
+        let canvas = canvas.lock().unwrap();
+        let painting = painting(&canvas);
+        assert!((1..=10).contains(&painting), "{painting} workers");
+        assert_eq!(painting, canvas.painted.len());
+
+        let threads =
+            |n| Arguments::try_parse_from(["pxlclnt", "-d", "x", "-t", n, "-s", "1", "size"]);
+        assert!(threads("1").is_ok());
+        assert!(threads("0").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn split_files_contain_every_command_once() {
+        let path = gradient("split.png");
+        let flags = format!("-t 3 -s 5 image 0 0 {}", path.display());
+        let files = split("split", &flags, 3).await;
+        assert!(files.iter().all(|file| !file.is_empty()));
+        let mut parts: Vec<&[u8]> = files
+            .iter()
+            .flat_map(|file| protocol::split_commands(file))
+            .collect();
+        parts.sort_unstable();
+
+        let whole = split("whole", &flags, 1).await;
+        let mut whole: Vec<&[u8]> = protocol::split_commands(&whole[0]).collect();
+        whole.sort_unstable();
+        assert_eq!(whole.len(), 64);
+        assert_eq!(parts, whole);
+    }
+
+    #[test]
+    fn encodes_compact_commands() {
+        let args = parse("-d localhost -t 1 -s 1 --protocol compact size");
+        let pixel = Pixel {
+            x: 258,
+            y: 3,
+            color: "ff8000".into(),
+        };
+        assert_eq!(pixel.encode(&args), b"PB\x02\x01\x03\x00\xff\x80\x00\xff");
+        let translucent = Pixel {
+            color: "0102037f".into(),
+            ..pixel
+        };
+        assert_eq!(translucent.encode(&args)[6..], [1, 2, 3, 0x7f]);
+        assert_eq!(translucent.encode(&args).len(), protocol::PB_LEN);
+
+        // the coordinates have 16 bit, pixels after them are dropped
+        let last = Pixel {
+            x: 65535,
+            y: 65535,
+            color: "ff8000".into(),
+        };
+        assert_eq!(last.encode(&args)[2..6], [0xff; 4]);
+        assert!(Pixel {
+            x: 65536,
+            ..last.clone()
+        }
+        .encode(&args)
+        .is_empty());
+        assert!(Pixel { y: 65536, ..last }.encode(&args).is_empty());
+    }
+
+    #[tokio::test]
+    async fn preconnect_check_fails_before_the_image_is_loaded() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let missing = temp("missing.png");
+        let line = format!("-d {address} -t 1 -s 1 image 0 0 {}", missing.display());
+        let args = parse(&format!("--preconnect-check {line}"));
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        let e = picture::run(&args, img).await.unwrap_err().to_string();
+        assert!(
+            e.starts_with(&format!("server {address} is not usable")),
+            "{e}"
+        );
+
+        // without the check the missing image fails first
+        let args = parse(&line);
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        let e = picture::run(&args, img).await.unwrap_err().to_string();
+        assert!(!e.contains("not usable"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn defends_the_region() {
+        let (address, canvas) = serve((8, 8)).await;
+        canvas
+            .lock()
+            .unwrap()
+            .pixels
+            .insert((2, 2), "ffffff".into());
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 1 -l defend 1 1 4 3 --color 00ff00 --interval 10"
+        ));
+        let Command::Defend(defend) = &args.command else {
+            unreachable!()
+        };
+        let defended = |canvas: &Canvas| {
+            (1..4)
+                .cartesian_product(1..3)
+                .all(|(x, y)| canvas.color(x, y) == "00ff00")
+        };
+        let attack = async {
+            settled(&canvas, defended).await;
+            // someone paints over the region
+            canvas
+                .lock()
+                .unwrap()
+                .pixels
+                .insert((3, 2), "ff0000".into());
+            settled(&canvas, defended).await;
+        };
+        tokio::select! {
+            result = guard(&args, defend) => panic!("defend stopped: {result:?}"),
+            () = attack => {}
+        }
+        let canvas = canvas.lock().unwrap();
+        assert_eq!(canvas.pixels.len(), 6);
+        assert_eq!(canvas.color(0, 0), "000000");
+    }
+
+    #[tokio::test]
+    async fn logs_the_pixels_that_failed_the_check() {
+        let (address, canvas) = serve((8, 8)).await;
+        canvas.lock().unwrap().rejected.extend([(1, 0), (2, 1)]);
+        let log = temp("errors.csv");
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 1 --check --error-log {} rect 0 0 3 2 ff0000",
+            log.display()
+        ));
+        let Command::Rect(painted) = &args.command else {
+            unreachable!()
+        };
+        rect(&args, painted).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(log).unwrap(),
+            "x,y,color,response\n1,0,ff0000,000000\n2,1,ff0000,000000\n"
+        );
+    }
+
+    #[test]
+    fn noise_stays_within_the_amount() {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let colors: Vec<String> = (0..1000).map(|_| noisy("80f00a", 12, &mut rng)).collect();
+        for color in &colors {
+            let [r, g, b, _] = rgba(color);
+            assert!((0x80 - 12..=0x80 + 12).contains(&r), "{color}");
+            assert!((0xf0 - 12..=0xff).contains(&g), "{color}");
+            assert!((0..=0x0a + 12).contains(&b), "{color}");
+        }
+        assert!(colors.iter().unique().count() > 100);
+        // the alpha is kept
+        assert!(noisy("80f00a7f", 12, &mut rng).ends_with("7f"));
+        assert_eq!(noisy("80f00a", 0, &mut rng), "80f00a");
+    }
+
+    #[tokio::test]
+    async fn checks_a_noisy_rect_against_its_noise() {
+        let (address, canvas) = serve((8, 8)).await;
+        let log = temp("noise.csv");
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 1 --check --error-log {} rect 0 0 4 4 808080 --noise 20",
+            log.display()
+        ));
+        let Command::Rect(painted) = &args.command else {
+            unreachable!()
+        };
+        rect(&args, painted).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(log).unwrap(),
+            "x,y,color,response\n"
+        );
+        assert!(canvas.lock().unwrap().pixels.values().unique().count() > 1);
+    }
+
+    #[tokio::test]
+    async fn reads_a_region_of_the_server() {
+        let (address, canvas) = serve((8, 8)).await;
+        canvas.lock().unwrap().pixels.extend([
+            ((5, 6), "ff0000".to_string()),
+            ((6, 7), "00ff7f".to_string()),
+        ]);
+        let args = parse(&format!("-d {address} -t 1 -s 1 --pipeline 3 size"));
+        let mut stream = sink::connect(&args).await.unwrap();
+        let grid = canvas::read_region(&mut stream, (5, 6), (2, 2), args.pipeline)
+            .await
+            .unwrap();
+        let color = |r, g, b| Color { r, g, b };
+        assert_eq!(
+            grid,
+            [
+                [color(255, 0, 0), color(0, 0, 0)],
+                [color(0, 0, 0), color(0, 255, 127)]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_server_closes_after_answering() {
+        let (address, canvas) = serve((8, 8)).await;
+        canvas.lock().unwrap().closes_after_answer = true;
+        let defend = "defend 0 0 1 1 --color 000000 --interval 10";
+        let args = parse(&format!("-d {address} -t 1 -s 1 -l {defend}"));
+        let Command::Defend(region) = &args.command else {
+            unreachable!()
+        };
+        guard(&args, region).await.unwrap();
+        // one connection for SIZE and one that defended
+        assert_eq!(canvas.lock().unwrap().connections, 2);
+
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 1 -l --retry-on-eof {defend}"
+        ));
+        let Command::Defend(region) = &args.command else {
+            unreachable!()
+        };
+        tokio::select! {
+            result = guard(&args, region) => panic!("defend stopped: {result:?}"),
+            () = settled(&canvas, |canvas| canvas.connections >= 6) => {}
+        }
+    }
+
+    #[test]
+    fn encodes_extended_commands() {
+        let args = parse("-d localhost -t 1 -s 1 --protocol extended size");
+        let encoded = |color: &str| {
+            let pixel = Pixel {
+                x: 1,
+                y: 2,
+                color: color.into(),
+            };
+            String::from_utf8(pixel.encode(&args)).unwrap()
+        };
+        assert_eq!(encoded("ff8001"), "PX 1 2 ffff80800101\n");
+        assert_eq!(encoded("ff80017f"), "PX 1 2 ffff808001017f7f\n");
+        assert_eq!(encoded("ffff12340001"), "PX 1 2 ffff12340001\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keeps_the_16_bit_of_the_source() {
+        let image = image::ImageBuffer::from_fn(1, 1, |_, _| image::Rgb([0xffffu16, 0x1234, 1]));
+        let mut png = std::io::Cursor::new(vec![]);
+        image::DynamicImage::ImageRgb16(image)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let path = temp("wide.png");
+        std::fs::write(&path, png.into_inner()).unwrap();
+        let flags = format!("-t 1 -s 1 --protocol extended image 2 3 {}", path.display());
+        assert_eq!(split("wide", &flags, 1).await, [b"PX 2 3 ffff12340001\n"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn split_modes_partition_every_pixel_once() {
+        let path = temp("modes.png");
+        std::fs::write(&path, png(40, 20, |x, y| [x as u8, y as u8, 1])).unwrap();
+        for mode in ["rows", "columns", "quadrants", "tiles"] {
+            let flags = format!("-t 4 -s 7 image 0 0 {} --split-mode {mode}", path.display());
+            // the workers paint the same number of pixels, so every file is a worker
+            let files = split(&format!("mode-{mode}"), &flags, 4).await;
+            let parts: Vec<Vec<(u32, u32)>> = files
+                .iter()
+                .map(|file| {
+                    protocol::split_commands(file)
+                        .map(|cmd| protocol::target(cmd).unwrap())
+                        .collect()
+                })
+                .collect();
+            let all: HashSet<(u32, u32)> = parts.iter().flatten().copied().collect();
+            assert_eq!(all.len(), 800, "{mode}");
+            assert_eq!(parts.concat().len(), 800, "{mode}");
+            assert!(parts.iter().all(|part| part.len() == 200), "{mode}");
+
+            let bounds = |part: &[(u32, u32)]| {
+                let (xs, ys): (Vec<u32>, Vec<u32>) = part.iter().copied().unzip();
+                let (x, y) = (xs.into_iter().minmax(), ys.into_iter().minmax());
+                (x.into_option().unwrap(), y.into_option().unwrap())
+            };
+            match mode {
+                "rows" => assert_eq!(bounds(&parts[1]), ((0, 39), (5, 9))),
+                "columns" => assert_eq!(bounds(&parts[2]), ((20, 29), (0, 19))),
+                "quadrants" => assert_eq!(bounds(&parts[3]), ((20, 39), (10, 19))),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resumes_with_the_pixels_that_were_not_sent() {
+        let (address, canvas) = serve((8, 8)).await;
+        let path = gradient("resume.png");
+        // a run that was interrupted after 20 pixels
+        let checkpoint = temp("resume.checkpoint");
+        let sent: Vec<(u32, u32)> = (0..20).map(|i| (i % 8, i / 8)).collect();
+        let lines: String = sent.iter().map(|(x, y)| format!("{x} {y}\n")).collect();
+        std::fs::write(&checkpoint, lines).unwrap();
+
+        let args = parse(&format!(
+            "-d {address} -t 2 -s 3 image 0 0 {} --checkpoint {} --resume {}",
+            path.display(),
+            checkpoint.display(),
+            checkpoint.display()
+        ));
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        picture::run(&args, img).await.unwrap();
+        settled(&canvas, |canvas| canvas.pixels.len() == 44).await;
+        let painted: Vec<String> = canvas.lock().unwrap().received.clone();
+        let painted: Vec<&String> = painted
+            .iter()
+            .filter(|line| line.split_whitespace().count() == 4)
+            .collect();
+        assert_eq!(painted.len(), 44);
+        assert!(sent
+            .iter()
+            .all(|pixel| !canvas.lock().unwrap().pixels.contains_key(pixel)));
+        let stored = std::fs::read_to_string(&checkpoint).unwrap();
+        assert_eq!(stored.lines().collect::<HashSet<_>>().len(), 64);
+
+        // everything is painted now
+        picture::run(&args, img).await.unwrap();
+        let received = canvas.lock().unwrap().received.len();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(canvas.lock().unwrap().received.len(), received);
+        assert_eq!(canvas.lock().unwrap().pixels.len(), 44);
+    }
+
+    #[test]
+    fn reorders_the_channels() {
+        assert_eq!(ChannelOrder::Bgr.arrange_hex("112233"), "332211");
+        assert_eq!(ChannelOrder::Grb.arrange_hex("11223344"), "22113344");
+        assert_eq!(
+            ChannelOrder::Bgr.arrange_hex("111122223333"),
+            "333322221111"
+        );
+        assert_eq!(ChannelOrder::Bgr.arrange_hex("7f"), "7f");
+        assert_eq!(ChannelOrder::Rgb.arrange_hex("112233"), "112233");
+
+        let args = parse("-d localhost -t 1 -s 1 --channel-order bgr --protocol compact size");
+        let pixel = Pixel {
+            x: 0,
+            y: 0,
+            color: "ff8000".into(),
+        };
+        assert_eq!(pixel.encode(&args)[6..], [0x00, 0x80, 0xff, 0xff]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_bursts_separated_by_idle_time() {
+        let args = parse("-d localhost -t 1 -s 1 --burst 4 --idle 100 rect 0 0 10 1 ff0000");
+        let Command::Rect(painted) = &args.command else {
+            unreachable!()
+        };
+        let cmds: Vec<Vec<u8>> = rect_cmds(painted, &args, &mut rng(&args)).collect();
+        let start = tokio::time::Instant::now();
+        let (mut sink, reader) = tokio::io::duplex(1024);
+        let reading = tokio::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            let mut arrived = vec![];
+            while let Ok(Some(_)) = lines.next_line().await {
+                arrived.push(start.elapsed().as_millis());
+            }
+            arrived
+        });
+        let mut progress = Progress::default();
+        let mut throttle = Throttle::new(&args);
+        paint(&mut sink, &cmds, false, &mut throttle, &mut progress)
+            .await
+            .unwrap();
+        drop(sink);
+        assert_eq!(
+            reading.await.unwrap(),
+            [0, 0, 0, 0, 100, 100, 100, 100, 200, 200]
+        );
+    }
+
+    #[tokio::test]
+    async fn clips_the_image_again_when_the_canvas_shrinks() {
+        let (address, canvas) = serve((8, 8)).await;
+        let path = gradient("resize.png");
+        let args = parse(&format!(
+            "-d {address} -t 2 -s 4 -l --burst 64 --idle 10 --resize-check 1 image 0 0 {}",
+            path.display()
+        ));
+        // the workers block their thread, the painting runs until the test ends
+        // and is paced so the server keeps up
+        std::thread::spawn(move || {
+            let Command::Image(img) = &args.command else {
+                unreachable!()
+            };
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async { picture::run(&args, img).await.map_err(|e| e.to_string()) })
+        });
+        settled(&canvas, |canvas| canvas.pixels.len() == 64).await;
+
+        canvas.lock().unwrap().size = (4, 3);
+        // the size is checked every second, commands that the server didn't
+        // process yet can still paint outside of the new canvas
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let mut canvas = canvas.lock().unwrap();
+            let pixels = std::mem::take(&mut canvas.pixels);
+            if pixels.len() == 12 && pixels.keys().all(|(x, y)| *x < 4 && *y < 3) {
+                return;
+            }
+        }
+        panic!("the image was not clipped to the new canvas");
+    }
+
+    #[test]
+    fn appends_the_sentinel_to_the_last_batch() {
+        let args = parse(r"-d localhost -t 1 -s 1 --final-sentinel \n\x00 size");
+        let mut task = vec![b"PX 0 0 ff\n".to_vec(), b"PX 1 0 ff\n".to_vec()];
+        finish_pass(&args, &mut task);
+        assert_eq!(
+            task,
+            [b"PX 0 0 ff\n".to_vec(), b"PX 1 0 ff\n\n\x00".to_vec()]
+        );
+
+        // the pass ends with the last batch that --dedupe keeps
+        let args = parse(r"-d localhost -t 1 -s 1 --dedupe --final-sentinel \r\n size");
+        let mut task = vec![b"PX 0 0 ff\n".to_vec(), b"PX 0 0 00\n".to_vec()];
+        finish_pass(&args, &mut task);
+        assert_eq!(task, [b"PX 0 0 00\n\r\n".to_vec()]);
+
+        let mut empty = vec![];
+        finish_pass(&args, &mut empty);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn parses_escaped_bytes() {
+        assert_eq!(
+            parse_bytes(r"a\n\r\t\\\x7f"),
+            Ok(Bytes(b"a\n\r\t\\\x7f".to_vec()))
+        );
+        assert!(parse_bytes(r"\x7").is_err());
+        assert!(parse_bytes(r"\x+7").is_err());
+        assert!(parse_bytes(r"\q").is_err());
+        assert!(parse_bytes("\\").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn plays_the_cells_of_a_sheet() {
+        let path = temp("sheet.png");
+        let sheet = png(4, 1, |x, _| [[255, 0, 0], [0, 255, 0]][x as usize / 2]);
+        std::fs::write(&path, sheet).unwrap();
+        let flags = format!("-t 1 -s 1 image 5 5 {} --sheet 2x1", path.display());
+        let commands = String::from_utf8(split("sheet", &flags, 1).await.concat()).unwrap();
+        assert_eq!(
+            commands,
+            "PX 5 5 ff0000\nPX 6 5 ff0000\nPX 5 5 00ff00\nPX 6 5 00ff00\n"
+        );
+
+        assert_eq!(parse_sheet("4x2"), Ok((4, 2)));
+        for invalid in ["4", "0x2", "4x", "x2", "-1x2"] {
+            assert!(parse_sheet(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_the_sampled_pixels_that_show_the_art() {
+        let (address, canvas) = serve((8, 8)).await;
+        canvas.lock().unwrap().pixels.extend([
+            ((2, 0), "ff0000".to_string()),
+            ((3, 0), "ff0000".to_string()),
+            ((4, 0), "00ff00".to_string()),
+        ]);
+        let args = parse(&format!("-d {address} -t 1 -s 1 --pipeline 4 size"));
+        let art: Vec<((u32, u32), [u8; 3])> = (1..5).map(|x| ((x, 0), [255, 0, 0])).collect();
+        let mut stream = sink::connect(&args).await.unwrap();
+        let sampled = sample(&args, &mut stream, &art, 1000, &mut rng(&args)).await;
+        assert_eq!(sampled.unwrap(), (2, 4));
+        let sampled = sample(&args, &mut stream, &art, 2, &mut rng(&args)).await;
+        assert_eq!(sampled.unwrap().1, 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_images_without_pixels_on_the_canvas() {
+        let path = gradient("cropped");
+        let cases = [
+            ("8 0", "no pixel of the image is on the canvas"),
+            ("0 0 --cover-fraction 0.01", "has no pixels"),
+        ];
+        for (flags, error) in cases {
+            let args = parse(&format!(
+                "-d localhost -t 1 -s 1 --canvas-x 8 --canvas-y 8 --dry-run image {flags} {}",
+                path.display()
+            ));
+            let image_args = match &args.command {
+                Command::Image(img) => img.clone(),
+                _ => unreachable!(),
+            };
+            let painted = picture::run(&args, &image_args).await;
+            assert!(painted.unwrap_err().to_string().contains(error), "{flags}");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn counts_the_top_colors_of_the_commands() {
+        let path = temp("top_colors.png");
+        let palette = [[255, 0, 0], [255, 0, 0], [255, 0, 0], [0, 255, 0]];
+        std::fs::write(&path, png(4, 2, |x, y| palette[((x + y) % 4) as usize])).unwrap();
+        let files = split(
+            "top_colors",
+            &format!("-t 1 -s 1 image 0 0 {}", path.display()),
+            1,
+        )
+        .await;
+        let colors = color_counts([files[0].clone()].iter());
+        let (red, green) = (String::from("ff0000"), String::from("00ff00"));
+        assert_eq!(top_colors(&colors, 5), [(&red, &6), (&green, &2)]);
+        assert_eq!(top_colors(&colors, 1), [(&red, &6)]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wipes_the_new_frame_column_by_column() {
+        // a sheet of a red and a blue frame of 4x2 pixels
+        let path = temp("wipe.png");
+        std::fs::write(
+            &path,
+            png(8, 2, |x, _| [(x < 4) as u8 * 255, 0, 255 * (x >= 4) as u8]),
+        )
+        .unwrap();
+        let flags = format!(
+            "-t 1 -s 1 image 0 0 {} --sheet 2x1 --transition wipe --transition-frames 4",
+            path.display()
+        );
+        let files = split("wipe", &flags, 1).await;
+        let lines: Vec<&str> = std::str::from_utf8(&files[0]).unwrap().lines().collect();
+        // every frame of the transition paints one more column of both rows
+        let bands: Vec<Vec<&str>> = lines
+            .chunks(2)
+            .map(|band| band.iter().copied().sorted().collect())
+            .collect();
+        let expected: Vec<Vec<String>> = ["ff0000", "0000ff"]
+            .into_iter()
+            .cartesian_product(0..4)
+            .map(|(color, x)| (0..2).map(|y| format!("PX {x} {y} {color}")).collect())
+            .collect();
+        assert_eq!(bands, expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn buffers_at_most_the_cap() {
+        let args = parse("-d localhost -t 1 -s 1 --max-buffer-bytes 40 rect 0 0 4 4 ff0000");
+        let Command::Rect(painted) = &args.command else {
+            unreachable!()
+        };
+        let generated = std::cell::Cell::new(0);
+        let mut rng = rng(&args);
+        let cmds = rect_cmds(painted, &args, &mut rng).inspect(|cmd| {
+            generated.set(generated.get() + cmd.len());
+        });
+        let (mut sent, mut parts_sent) = (0, vec![]);
+        for (part, last) in parts(cmds, 40) {
+            // a slow connection that takes its time for every part
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let bytes: usize = part.iter().map(|cmd| cmd.len()).sum();
+            assert!(bytes <= 40, "{bytes}");
+            // only the next command is generated ahead of the part
+            assert!(generated.get() - sent <= 40 + "PX 0 0 ff0000\n".len());
+            sent += bytes;
+            parts_sent.push(last);
+        }
+        assert_eq!(sent, generated.get());
+        assert_eq!(parts_sent.iter().filter(|last| **last).count(), 1);
+        assert_eq!(parts_sent.last(), Some(&true));
+        // commands bigger than the cap are still sent
+        let big = parts([vec![0; 50], vec![0; 10]].into_iter(), 40);
+        assert_eq!(big.map(|(part, _)| part.len()).collect::<Vec<_>>(), [1, 1]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spawns_no_worker_that_is_idle_in_every_pass() {
+        let (address, canvas) = serve((4, 2)).await;
+        // two frames of 4x2, every band of the wipe is a column of 2 pixels
+        let path = temp("bands.png");
+        std::fs::write(&path, png(8, 2, |x, y| [x as u8 + 1, y as u8 + 1, 0])).unwrap();
+        // 8 pixels are enough for 4 workers, but 2 of them get no pixel of a band
+        let args = parse(&format!(
+            "-d {address} -t 4 -s 1 image --sheet 2x1 --transition wipe --transition-frames 4 0 0 {}",
+            path.display()
+        ));
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        let totals = picture::run(&args, img).await.unwrap();
+        assert_eq!(totals.commands, 16);
+        settled(&canvas, |canvas| canvas.received.len() >= 16).await;
+        let canvas = canvas.lock().unwrap();
+        assert_eq!(painting(&canvas), 2);
+        assert_eq!(canvas.painted.len(), 2);
+        for (x, y) in (0..4).cartesian_product(0..2) {
+            assert_eq!(canvas.color(x, y), format!("{:02x}{:02x}00", x + 5, y + 1));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queues_at_most_the_cap_for_a_slow_server() {
+        let (address, canvas) = serve((40, 40)).await;
+        canvas.lock().unwrap().read_delay = Some(Duration::from_micros(200));
+        let path = temp("slow.png");
+        std::fs::write(&path, png(40, 40, |x, y| [x as u8 + 1, y as u8 + 1, 0])).unwrap();
+        let cap = 200;
+        let args = parse(&format!(
+            "-d {address} -t 2 -s 4 --send-buffer 1 --max-buffer-bytes {cap} image 0 0 {}",
+            path.display()
+        ));
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        let totals = picture::run(&args, img).await.unwrap();
+        // the server kept the generation waiting for the budget
+        assert!(totals.peak_queued <= cap, "{totals:?}");
+        assert!(totals.peak_queued > cap / 2, "{totals:?}");
+        assert_eq!(totals.commands, 1600);
+        settled(&canvas, |canvas| canvas.pixels.len() == 1600).await;
+        let canvas = canvas.lock().unwrap();
+        for (x, y) in (0..40).cartesian_product(0..40) {
+            assert_eq!(canvas.color(x, y), format!("{:02x}{:02x}00", x + 1, y + 1));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_batches_of_size_0_and_skips_idle_workers() {
+        let size =
+            |n| Arguments::try_parse_from(["pxlclnt", "-d", "x", "-t", "1", "-s", n, "size"]);
+        assert!(size("1").is_ok());
+        assert_eq!(
+            size("0").unwrap_err().kind(),
+            clap::error::ErrorKind::ValueValidation
+        );
+
+        // most of the threads would get no pixel of the image at all
+        let path = temp("idle.png");
+        std::fs::write(&path, png(2, 1, |x, _| [x as u8 + 1, 0, 0])).unwrap();
+        let flags = format!("-t 8 -s 3 image 0 0 {}", path.display());
+        let files = split("idle", &flags, 1).await;
+        let lines: Vec<&str> = std::str::from_utf8(&files[0])
+            .unwrap()
+            .lines()
+            .sorted()
+            .collect();
+        assert_eq!(lines, ["PX 0 0 010000", "PX 1 0 020000"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_at_the_recorded_timing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let path = temp("timed");
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 1 --record {} --record-timed replay {}",
+            path.display(),
+            path.display()
+        ));
+        let pause = Duration::from_millis(200);
+        let mut stream = sink::connect_recorded(&args).await.unwrap();
+        let _recorded_server = listener.accept().await.unwrap();
+        for cmd in ["PX 0 0 ff0000\n", "PX 1 0 00ff00\n"] {
+            stream.write_all(cmd.as_bytes()).await.unwrap();
+            stream.flush().await.unwrap();
+            tokio::time::sleep(pause).await;
+        }
+        // the recording is complete once the connection is dropped
+        drop(stream);
+
+        let recording = std::fs::read(&path).unwrap();
+        let writes = parse_recording(&recording).unwrap();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[1].1, b"PX 1 0 00ff00\n");
+        let recorded = Duration::from_micros(writes[1].0 - writes[0].0);
+        assert!(recorded >= pause, "{recorded:?}");
+        assert_eq!(parse_recording(b"@12 5\nPX 0"), None);
+
+        let Command::Replay(replay) = &args.command else {
+            unreachable!()
+        };
+        let arrivals = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let mut arrivals = vec![];
+            while let Some(line) = lines.next_line().await.unwrap() {
+                arrivals.push((tokio::time::Instant::now(), line));
+            }
+            arrivals
+        });
+        let args = parse(&format!("-d {address} -t 1 -s 1 size"));
+        play(&args, replay).await.unwrap();
+        let arrivals = arrivals.await.unwrap();
+        assert_eq!(arrivals.len(), 2);
+        let replayed = arrivals[1].0 - arrivals[0].0;
+        assert!(
+            replayed + Duration::from_millis(20) >= recorded,
+            "{replayed:?}"
+        );
+        assert!(
+            replayed < recorded + Duration::from_millis(150),
+            "{replayed:?}"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recommends_short_colors_for_a_gray_image() {
+        let path = temp("gray.png");
+        std::fs::write(&path, png(4, 4, |x, y| [(x * 4 + y) as u8 * 16; 3])).unwrap();
+        let flags = format!("-t 1 -s 1 image 0 0 {}", path.display());
+        let files = split("gray", &flags, 1).await;
+        let savings = Savings::estimate(&color_counts([files[0].clone()].iter()));
+        // every command saves 4 of the 6 digits
+        assert_eq!(savings.gray, 16 * 4);
+        assert_eq!(savings.alpha, 0);
+        assert_eq!(savings.best(), Some(("--short-colors", 16 * 4)));
+        assert_eq!(Savings::default().best(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn uses_extended_colors_for_a_depth_of_16_bit() {
+        let (address, canvas) = serve((8, 6)).await;
+        let line = format!("-d {address} -t 1 -s 1 rect 0 0 1 1 ff0000");
+        for (depth, extended) in [(None, false), (Some(24), false), (Some(48), true)] {
+            canvas.lock().unwrap().depth = depth;
+            let args = parse(&line);
+            assert_eq!(size(&args).await.unwrap(), (8, 6), "{depth:?}");
+            assert_eq!(extended_supported(&args).await, extended, "{depth:?}");
+
+            // without --protocol the depth chooses the commands
+            let mut chosen = parse(&line);
+            choose_protocol(&mut chosen, false).await;
+            let expected = match extended {
+                true => Protocol::Extended,
+                false => Protocol::Standard,
+            };
+            assert_eq!(chosen.protocol, expected, "{depth:?}");
+            // an explicit --protocol standard is kept
+            let mut standard = parse(&line);
+            choose_protocol(&mut standard, true).await;
+            assert_eq!(standard.protocol, Protocol::Standard, "{depth:?}");
+        }
+    }
+
+    #[test]
+    fn loads_misnamed_images_by_their_content() {
+        let path = temp("image.dat");
+        std::fs::write(&path, png(3, 2, |x, y| [x as u8, y as u8, 7])).unwrap();
+        for format in [None, Some(image::ImageFormat::Png)] {
+            let frames = load_image_as(&path, format).unwrap();
+            assert_eq!(frames.len(), 1);
+            assert_eq!((frames[0].width(), frames[0].height()), (3, 2));
+            assert_eq!(frames[0].to_rgb8().get_pixel(2, 1).0, [2, 1, 7]);
+        }
+        // a wrong override is not corrected
+        assert!(load_image_as(&path, Some(image::ImageFormat::Jpeg)).is_err());
+        assert_eq!(parse_format("jpg"), Ok(image::ImageFormat::Jpeg));
+        assert!(parse_format("dat").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rewinds_to_the_batches_that_could_be_lost() {
+        let batch = |commands: usize| "PX 0 0 ff0000\n".repeat(commands).into_bytes();
+        let task = vec![batch(1), batch(2), batch(3)];
+        // the batch, commands and resent commands after a rewind
+        let rewound = |batches: usize, buffered| {
+            let mut progress = Progress::default();
+            for cmd in &task[..batches] {
+                progress.sent(cmd, protocol::split_commands(cmd).count());
+                progress.batch += 1;
+            }
+            progress.rewind(&task, buffered);
+            (progress.batch, progress.commands, progress.resent)
+        };
+        assert_eq!(rewound(3, 0), (3, 6, 0));
+        assert_eq!(rewound(3, 42), (2, 3, 3));
+        assert_eq!(rewound(3, 43), (1, 1, 5));
+        assert_eq!(rewound(3, 1000), (0, 0, 6));
+        assert_eq!(rewound(2, 28), (1, 1, 2));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnects_only_the_worker_that_failed() {
+        let (address, canvas) = serve((20, 20)).await;
+        canvas.lock().unwrap().resets_after = Some(20);
+        let path = temp("reset.png");
+        std::fs::write(&path, png(20, 20, |x, y| [x as u8 + 1, y as u8 + 1, 0])).unwrap();
+        // the bursts keep the workers writing after the reset arrived
+        let args = parse(&format!(
+            "-d {address} -t 2 -s 10 --burst 20 --idle 20 --reconnect image 0 0 {}",
+            path.display()
+        ));
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        let totals = picture::run(&args, img).await.unwrap();
+        // the batches that were sent again count once, so every pixel is one command
+        assert_eq!(totals.commands, 400, "{totals:?}");
+        let bytes = (0..20)
+            .cartesian_product(0..20)
+            .map(|(x, y)| format!("PX {x} {y} 000000\n").len());
+        assert_eq!(totals.bytes, bytes.sum::<usize>(), "{totals:?}");
+        assert_eq!(totals.reconnects, 1);
+        assert_eq!(totals.failed, 0);
+        assert!((1..=200).contains(&totals.resent), "{totals:?}");
+        settled(&canvas, |canvas| canvas.pixels.len() == 400).await;
+        let canvas = canvas.lock().unwrap();
+        for (x, y) in (0..20).cartesian_product(0..20) {
+            assert_eq!(canvas.color(x, y), format!("{:02x}{:02x}00", x + 1, y + 1));
+        }
+        // the other worker painted its half once, without a new connection
+        let mut painted: Vec<usize> = canvas.painted.values().copied().collect();
+        painted.sort();
+        assert_eq!(painted.len(), 3, "{painted:?}");
+        assert_eq!(painted[0], 20);
+        assert!(painted.contains(&200), "{painted:?}");
+        // only the batches the reset could have lost were sent again
+        assert!(
+            painted.iter().sum::<usize>() <= 400 + totals.resent,
+            "{painted:?}"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slows_down_while_the_server_resets_the_connections() {
+        let (address, canvas) = serve((10, 10)).await;
+        {
+            let mut canvas = canvas.lock().unwrap();
+            canvas.resets_after = Some(1);
+            canvas.keeps_resetting = true;
+        }
+        // the pause after every pixel lets the reset arrive before the next
+        // write, so every second write fails
+        let args = parse(&format!(
+            "-d {address} -t 1 -s 1 --burst 1 --idle 2 --reconnect rect 0 0 10 10 ff0000"
+        ));
+        let Command::Rect(painted) = &args.command else {
+            unreachable!()
+        };
+        rect(&args, painted).await.unwrap();
+        settled(&canvas, |canvas| canvas.pixels.len() == 100).await;
+        let canvas = canvas.lock().unwrap();
+        assert!(canvas.connections > 50, "{}", canvas.connections);
+        let arrived: Vec<std::time::Instant> = canvas
+            .received
+            .iter()
+            .zip(&canvas.arrived)
+            .filter(|(line, _)| line.starts_with("PX"))
+            .map(|(_, arrived)| *arrived)
+            .collect();
+        assert_eq!(arrived.len(), 100);
+        let gaps: Vec<Duration> = arrived.windows(2).map(|w| w[1] - w[0]).collect();
+        // every window of 50 writes with failures doubles the delay, as every
+        // second write fails a window is about 25 pixels
+        let median = |gaps: &[Duration]| gaps.iter().sorted().nth(gaps.len() / 2).copied();
+        let medians: Vec<Duration> = gaps.chunks(25).flat_map(median).collect();
+        assert!(medians.windows(2).all(|m| m[0] < m[1]), "{medians:?}");
+        assert!(
+            medians[3] >= medians[0] + Duration::from_millis(4),
+            "{medians:?}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn starts_every_batch_with_the_offset() {
+        let path = temp("offset-batch.png");
+        std::fs::write(&path, png(4, 3, |x, y| [x as u8 + 1, y as u8 + 1, 0])).unwrap();
+        for (flags, offsets) in [
+            ("", 1),
+            ("--offset-per-batch", 4),
+            ("--offset-per-batch --max-buffer-bytes 1000", 4),
+        ] {
+            let (address, canvas) = serve((16, 16)).await;
+            let args = parse(&format!(
+                "-d {address} -t 1 -s 3 --offset {flags} image 2 1 {}",
+                path.display()
+            ));
+            let Command::Image(img) = &args.command else {
+                unreachable!()
+            };
+            picture::run(&args, img).await.unwrap();
+            settled(&canvas, |canvas| canvas.pixels.len() == 12).await;
+            let canvas = canvas.lock().unwrap();
+            // the commands of the painting connection follow the probe for OFFSET
+            let probe = canvas
+                .received
+                .iter()
+                .rposition(|line| line == "SIZE")
+                .unwrap();
+            let painted = &canvas.received[probe + 1..];
+            assert_eq!(painted.len(), 12 + offsets, "{flags}");
+            let step = 12 / offsets + 1;
+            for (i, line) in painted.iter().enumerate() {
+                assert_eq!(line == "OFFSET 2 1", i % step == 0, "{flags} {i}: {line}");
+            }
+            assert_eq!(canvas.color(5, 3), "040300");
+        }
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use image::{Rgb, RgbImage};
+
+/// upper limit of iterations of the k-means clustering
+const MAX_ITERATIONS: usize = 32;
+
+fn distance(a: &[u8; 3], b: &[u8; 3]) -> u32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (*a as i32 - *b as i32).pow(2) as u32)
+        .sum()
+}
+
+/// the color of the palette that is closest to the color
+pub fn nearest<'a>(palette: &'a [[u8; 3]], color: &[u8; 3]) -> &'a [u8; 3] {
+    palette
+        .iter()
+        .min_by_key(|entry| distance(entry, color))
+        .expect("palette is not empty")
+}
+
+/// finds up to `size` representative colors of an image with k-means clustering
+pub fn extract(image: &RgbImage, size: usize) -> Vec<[u8; 3]> {
+    let mut counts: HashMap<[u8; 3], u64> = HashMap::new();
+    for pxl in image.pixels() {
+        *counts.entry(pxl.0).or_default() += 1;
+    }
+    extract_counted(counts, size)
+}
+
+/// finds up to `size` representative colors of colors that appear this often
+pub fn extract_counted(counts: HashMap<[u8; 3], u64>, size: usize) -> Vec<[u8; 3]> {
+    // cluster the distinct colors weighted by how often they appear
+    let mut colors: Vec<([u8; 3], u64)> = counts.into_iter().collect();
+    // the order of a HashMap changes between runs
+    colors.sort_unstable();
+    if colors.len() <= size {
+        return colors.into_iter().map(|(color, _)| color).collect();
+    }
+
+    // start with the most common color and add the colors farthest away from
+    // the chosen ones, so that distinct colors get their own cluster
+    let mut centers: Vec<[u8; 3]> = vec![colors.iter().max_by_key(|(_, n)| *n).unwrap().0];
+    while centers.len() < size {
+        let farthest = colors
+            .iter()
+            .max_by_key(|(color, _)| distance(nearest(&centers, color), color))
+            .unwrap();
+        centers.push(farthest.0);
+    }
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut sums = vec![([0u64; 3], 0u64); centers.len()];
+        for (color, count) in &colors {
+            let (i, _) = centers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, center)| distance(center, color))
+                .unwrap();
+            for (sum, channel) in sums[i].0.iter_mut().zip(color.iter()) {
+                *sum += *channel as u64 * count;
+            }
+            sums[i].1 += count;
+        }
+
+        let updated: Vec<[u8; 3]> = sums
+            .iter()
+            .zip(centers.iter())
+            .map(|((sum, count), center)| match count {
+                0 => *center,
+                count => sum.map(|channel| ((channel + count / 2) / count) as u8),
+            })
+            .collect();
+        if updated == centers {
+            break;
+        }
+        centers = updated;
+    }
+    centers
+}
+
+/// replaces every color of the image with the nearest color of the palette
+pub fn apply(image: &mut RgbImage, palette: &[[u8; 3]]) {
+    let mut cache: HashMap<[u8; 3], [u8; 3]> = HashMap::new();
+    for pxl in image.pixels_mut() {
+        let mapped = *cache
+            .entry(pxl.0)
+            .or_insert_with(|| *nearest(palette, &pxl.0));
+        *pxl = Rgb(mapped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an image with a column for every color
+    fn columns(colors: &[[u8; 3]]) -> RgbImage {
+        RgbImage::from_fn(colors.len() as u32, 4, |x, _| Rgb(colors[x as usize]))
+    }
+
+    #[test]
+    fn extracts_the_centers_of_a_noisy_two_color_image() {
+        let image = columns(&[
+            [250, 0, 0],
+            [0, 0, 250],
+            [254, 4, 0],
+            [4, 0, 254],
+            [246, 0, 4],
+            [0, 4, 246],
+            [250, 8, 0],
+            [8, 0, 250],
+        ]);
+        let mut palette = extract(&image, 2);
+        palette.sort_unstable();
+        assert_eq!(palette, [[3, 1, 250], [250, 3, 1]]);
+        // images with fewer colors than the palette keep their colors
+        let mut palette = extract(&columns(&[[255, 0, 0], [0, 0, 255]]), 4);
+        palette.sort_unstable();
+        assert_eq!(palette, [[0, 0, 255], [255, 0, 0]]);
+    }
+
+    #[test]
+    fn clusters_similar_colors() {
+        let image = columns(&[[200, 0, 0], [0, 0, 100], [210, 0, 0], [0, 0, 110]]);
+        let mut palette = extract(&image, 2);
+        palette.sort_unstable();
+        assert_eq!(palette, [[0, 0, 105], [205, 0, 0]]);
+    }
+
+    #[test]
+    fn maps_to_the_nearest_color() {
+        let mut image = columns(&[[200, 10, 10], [10, 10, 90]]);
+        apply(&mut image, &[[255, 0, 0], [0, 0, 255]]);
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(image.get_pixel(1, 3), &Rgb([0, 0, 255]));
+    }
+}
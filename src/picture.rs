@@ -0,0 +1,1078 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    error::Error,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use itertools::Itertools;
+use rand::seq::SliceRandom;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    add_noise, check, dry_run, finish_pass, load_image, load_image_as, offset_supported,
+    output_commands_stats, paint, palette, pixel, preconnect_check, protocol, query_size,
+    read_response, reconnect, record_history, rgba, rng, sink, sink::Sink, size, Align, Arguments,
+    Bytes, Image, Pixel, Progress, Protocol, SplitMode, Throttle, Transition,
+};
+
+/// what the workers of an image sent together
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Totals {
+    /// the commands and bytes of every batch once, also of the batches that
+    /// were sent again after a reconnect
+    pub commands: usize,
+    pub bytes: usize,
+    /// commands that were sent again because they could have been lost
+    pub resent: usize,
+    pub reconnects: usize,
+    /// workers that stopped before they were done
+    pub failed: usize,
+    /// with --max-buffer-bytes the most bytes that were generated but not
+    /// written yet
+    pub peak_queued: usize,
+}
+
+/// paints image with an offset
+/// animated images are painted frame by frame
+/// with --resize-check the image is fitted again whenever the canvas changes
+/// returns what was sent for the image as it was fitted last
+pub async fn run(args: &Arguments, img: &Image) -> Result<Totals, Box<dyn Error>> {
+    let mut first = true;
+    loop {
+        if let Some(totals) = paint_image(args, img, first).await? {
+            return Ok(totals);
+        }
+        first = false;
+    }
+}
+
+/// paints image until it is done or, with --resize-check, the canvas changed
+/// returns nothing if the canvas changed
+async fn paint_image(
+    args: &Arguments,
+    img: &Image,
+    first: bool,
+) -> Result<Option<Totals>, Box<dyn Error>> {
+    //preparation from here till core loop
+    if args.preconnect_check {
+        preconnect_check(args).await?;
+    }
+    let mut frames = load_image_as(&img.path, img.format)?;
+    if let Some(sheet) = img.sheet {
+        if frames.len() > 1 {
+            return Err("--sheet needs a still image".into());
+        }
+        frames = cells(&frames[0], sheet)?;
+    }
+    if img.checkpoint.is_some() && (frames.len() > 1 || args.loops) {
+        return Err("--checkpoint works only with still images and without loops".into());
+    }
+    // the batches are generated while painting and dropped once they are sent
+    let streamed = args.max_buffer_bytes.filter(|_| !args.is_dry_run());
+    if streamed.is_some() && (img.checkpoint.is_some() || args.framerate.is_some()) {
+        return Err("--max-buffer-bytes doesn't work with --checkpoint or --framerate".into());
+    }
+    let done = match &img.resume {
+        Some(path) => load_checkpoint(path).await?,
+        None => HashSet::new(),
+    };
+    let canvas_limit = size(args).await?;
+
+    // all frames have the size of the first one
+    let (mut x, mut y) = (img.x, img.y);
+    let mut scaled = None;
+    if let Some(fraction) = img.cover_fraction {
+        let (dimensions, centered) = cover(
+            (frames[0].width(), frames[0].height()),
+            canvas_limit,
+            fraction,
+        );
+        if dimensions.0 == 0 || dimensions.1 == 0 {
+            return Err(format!(
+                "image scaled to cover {fraction} of the canvas {canvas_limit:?} has no pixels"
+            )
+            .into());
+        }
+        scaled = Some(dimensions);
+        x += centered.0;
+        y += centered.1;
+    }
+    if let Some(align) = img.align {
+        let dimensions = scaled.unwrap_or((frames[0].width(), frames[0].height()));
+        let (aligned_x, aligned_y) = aligned(align, img.margin, dimensions, canvas_limit);
+        x = aligned_x.unwrap_or(x);
+        y = aligned_y.unwrap_or(y);
+    }
+    let frames: Vec<image::DynamicImage> = frames
+        .into_iter()
+        .map(|frame| match scaled {
+            Some((width, height)) => {
+                frame.resize_exact(width, height, image::imageops::FilterType::Triangle)
+            }
+            None => frame,
+        })
+        .map(|frame| match &img.over {
+            Some(background) => over(&frame, rgba(background)),
+            None => frame,
+        })
+        .collect();
+    let frames = match img.transition {
+        Transition::Fade if frames.len() > 1 => fade(&frames, img.transition_frames, args.loops),
+        _ => frames,
+    };
+    // extended colors keep the 16 bit of the source
+    let wide: Option<Vec<_>> = (args.protocol == Protocol::Extended)
+        .then(|| frames.iter().map(|frame| frame.to_rgb16()).collect());
+    if wide.is_some() && (img.palette_from.is_some() || img.noise > 0) {
+        return Err("--palette-from and --noise don't work with --protocol extended".into());
+    }
+    let mut frames: Vec<image::RgbImage> = frames.iter().map(|frame| frame.to_rgb8()).collect();
+    if let Some(path) = &img.palette_from {
+        let reference = load_image(path)?.swap_remove(0).to_rgb8();
+        let palette = palette::extract(&reference, img.palette_size as usize);
+        for frame in frames.iter_mut() {
+            palette::apply(frame, &palette);
+        }
+    }
+    if img.noise > 0 {
+        let mut rng = rng(args);
+        for pxl in frames.iter_mut().flat_map(|frame| frame.pixels_mut()) {
+            add_noise(&mut pxl.0, img.noise, &mut rng);
+        }
+    }
+    if frames[0].width() > canvas_limit.0 || frames[0].height() > canvas_limit.1 {
+        println!("WARN: image is bigger than canvas size");
+    }
+
+    // collect all pixels of a frame
+    let pixels = |frame: usize| -> Vec<Pixel> {
+        if let Some(wide) = &wide {
+            return wide[frame]
+                .enumerate_pixels()
+                .map(|pxl| Pixel {
+                    x: x + pxl.0,
+                    y: y + pxl.1,
+                    color: format!(
+                        "{:04x?}{:04x?}{:04x?}",
+                        pxl.2 .0[0], pxl.2 .0[1], pxl.2 .0[2]
+                    ),
+                })
+                .collect();
+        }
+        frames[frame]
+            .enumerate_pixels()
+            .map(|pxl| Pixel {
+                x: x + pxl.0,
+                y: y + pxl.1,
+                color: format!(
+                    "{:02x?}{:02x?}{:02x?}",
+                    pxl.2 .0[0], pxl.2 .0[1], pxl.2 .0[2]
+                ),
+            })
+            .collect()
+    };
+
+    // the pixels keep their position on the canvas, with OFFSET the commands
+    // are relative to the image position instead
+    let offset = args.offset && offset_supported(args).await;
+    let to_cmd = |pxl: &Pixel| {
+        if offset {
+            let relative = Pixel {
+                x: pxl.x - x,
+                y: pxl.y - y,
+                color: pxl.color.clone(),
+            };
+            relative.encode(args)
+        } else {
+            pxl.encode(args)
+        }
+    };
+
+    // an image that is cropped away entirely would silently paint nothing
+    let on_canvas = |pxl: &Pixel| pxl.x < canvas_limit.0 && pxl.y < canvas_limit.1;
+    if !pixels(0).iter().any(on_canvas) {
+        return Err("no pixel of the image is on the canvas".into());
+    }
+    // a worker without pixels would only waste a connection
+    let visible = |pxl: &Pixel| on_canvas(pxl) && !done.contains(&(pxl.x, pxl.y));
+    let pixel_count = pixels(0).iter().filter(|pxl| visible(pxl)).count();
+    if pixel_count == 0 {
+        // e.g. the checkpoint of --resume already covers every pixel
+        println!("every pixel of the image is already painted");
+        return Ok(Some(Totals::default()));
+    }
+    let (workers, clamped) = clamp_workers(args.threads, pixel_count);
+    if let Some(warning) = clamped {
+        println!("{warning}");
+    }
+
+    // with a wipe every frame of an animation is painted in bands of columns
+    let dimensions = frames[0].dimensions();
+    let bands = match img.transition {
+        Transition::Wipe if frames.len() > 1 => img.transition_frames.min(dimensions.0),
+        _ => 1,
+    };
+    // the visible pixels of every pass, sorted by the part of the worker
+    let passes = || {
+        (0..frames.len()).flat_map(|frame| {
+            let frame = pixels(frame);
+            (0..bands).map(move |band| {
+                let columns = (dimensions.0 * band / bands)..(dimensions.0 * (band + 1) / bands);
+                let mut pass: Vec<Pixel> = frame
+                    .iter()
+                    .filter(|pxl| columns.contains(&(pxl.x - x)) && visible(pxl))
+                    .cloned()
+                    .collect();
+                pass.sort_by_cached_key(|pxl| {
+                    split_key(img.split_mode, workers, dimensions, (pxl.x - x, pxl.y - y))
+                });
+                pass
+            })
+        })
+    };
+    // the part of a pass of a worker, the parts differ by one pixel at most
+    let part = |pass: &[Pixel], i: usize| -> Vec<Pixel> {
+        pass[(pass.len() * i / workers)..(pass.len() * (i + 1) / workers)].to_vec()
+    };
+
+    // every worker gets the same part of every frame
+    let mut rng = rng(args);
+    let mut groups: Vec<Vec<Vec<Vec<u8>>>> = vec![vec![]; workers];
+    // the pixels of every batch of every worker, only for checkpoints
+    let mut batches: Vec<Vec<Vec<(u32, u32)>>> = vec![vec![]; workers];
+    // e.g. every band of a wipe can have fewer pixels than there are workers
+    let mut idle = vec![true; workers];
+    if streamed.is_some() {
+        for pass in passes() {
+            for (i, idle) in idle.iter_mut().enumerate() {
+                *idle &= (pass.len() * i / workers) == (pass.len() * (i + 1) / workers);
+            }
+        }
+    } else {
+        for all_pixels in passes() {
+            // divide pixels for threads
+            let mut tasks: Vec<Vec<Vec<u8>>> = vec![];
+            for (i, batch) in batches.iter_mut().enumerate() {
+                let mut pxls = part(&all_pixels, i);
+                idle[i] &= pxls.is_empty();
+                if img.shuffle {
+                    pxls.shuffle(&mut rng);
+                }
+                if img.checkpoint.is_some() {
+                    if offset && !args.offset_per_batch {
+                        batch.push(vec![]);
+                    }
+                    let chunks = pxls.iter().chunks(args.size as usize);
+                    for chunk in &chunks {
+                        batch.push(chunk.map(|pxl| (pxl.x, pxl.y)).collect());
+                    }
+                }
+                tasks.push(pxls.iter().map(to_cmd).collect());
+            }
+
+            // group commands
+            for (group, span) in groups.iter_mut().zip(tasks.iter()) {
+                let mut result: Vec<Vec<u8>> = vec![];
+                let offset_cmd = format!("OFFSET {x} {y}\n").into_bytes();
+                let chunks = span.iter().chunks(args.size as usize);
+                chunks.into_iter().for_each(|c| {
+                    let mut cmd = vec![];
+                    if offset && args.offset_per_batch {
+                        cmd.extend_from_slice(&offset_cmd);
+                    }
+                    for i in c {
+                        cmd.extend_from_slice(i);
+                    }
+                    result.push(cmd);
+                });
+                if offset && !args.offset_per_batch {
+                    // every worker has its own connection so every one needs the offset
+                    result.insert(0, offset_cmd);
+                }
+                finish_pass(args, &mut result);
+                group.push(result);
+            }
+        }
+    }
+
+    if args.is_dry_run() {
+        // frame after frame like they are painted
+        let passes: Vec<Vec<Vec<u8>>> = (0..groups[0].len())
+            .flat_map(|frame| groups.iter().map(move |group| group[frame].clone()))
+            .collect();
+        dry_run(args, &passes).await?;
+        return Ok(Some(Totals::default()));
+    }
+    if first {
+        output_commands_stats(args, groups.iter().flatten().flatten());
+    }
+
+    // the pixels outside of the canvas are never painted so they are not stored
+    // after a resize the history would store the image itself
+    if first {
+        record_history(
+            args,
+            pixels(0)
+                .iter()
+                .filter(|pxl| visible(pxl))
+                .map(|pxl| (pxl.x, pxl.y)),
+        )
+        .await?;
+    }
+
+    // core loop
+    // spawn threads that work on pixels
+    // the threads share the start so they show the same frame at the same time
+    let start = tokio::time::Instant::now();
+    let tasks = Arc::new(RwLock::new(groups));
+    let batches = Arc::new(batches);
+    let resized = Arc::new(AtomicBool::new(false));
+    // with --offset-per-batch every batch already starts with the OFFSET
+    let offset_cmd =
+        (offset && !args.offset_per_batch).then(|| format!("OFFSET {x} {y}\n").into_bytes());
+    let mut senders = vec![];
+    let mut handles = vec![];
+    for i in 0..workers {
+        if idle[i] {
+            senders.push(None);
+            continue;
+        }
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let receiver = streamed.map(|_| receiver);
+        senders.push(streamed.map(|_| sender));
+        let task = tasks.clone();
+        let offset_cmd = offset_cmd.clone();
+        let batches = batches.clone();
+        let checkpoint = img.checkpoint.clone();
+        let resized = resized.clone();
+        let args = args.clone();
+        let handle = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let task = task.read().unwrap()[i].clone();
+                    tokio::select! {
+                        progress = work(&args, &task, start, checkpoint.as_deref(), &batches[i], offset_cmd.as_deref(), receiver) => {
+                            Some(progress)
+                        }
+                        _ = stopped(&resized) => None,
+                    }
+                })
+        });
+        handles.push(handle);
+    }
+
+    // the workers stop when the canvas changed
+    let overridden = args.canvas_x.is_some() && args.canvas_y.is_some();
+    let watcher = args.resize_check.filter(|_| !overridden).map(|secs| {
+        let args = args.clone();
+        let resized = resized.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(secs)).await;
+                match query_size(&args).await {
+                    Ok(size) if size != canvas_limit => {
+                        println!(
+                            "WARN: canvas changed from {canvas_limit:?} to {size:?}, fitting the image again"
+                        );
+                        resized.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("WARN: could not check the canvas size: {e}"),
+                }
+            }
+        })
+    });
+
+    // with --max-buffer-bytes the batches are generated while the workers
+    // paint, in turns so every worker gets its next batch before one gets two
+    let mut peak_queued = 0;
+    if let Some(cap) = streamed {
+        let cap = cap.min(u32::MAX as usize);
+        let budget = Arc::new(tokio::sync::Semaphore::new(cap));
+        let size = args.size as usize;
+        let offset_cmd = format!("OFFSET {x} {y}\n").into_bytes();
+        'generating: loop {
+            for pass in passes() {
+                let mut parts: Vec<Vec<Pixel>> = (0..workers).map(|i| part(&pass, i)).collect();
+                if img.shuffle {
+                    for part in parts.iter_mut() {
+                        part.shuffle(&mut rng);
+                    }
+                }
+                let longest = parts.iter().map(Vec::len).max().unwrap_or(0);
+                for first in (0..longest).step_by(size) {
+                    for (part, sender) in parts.iter().zip(&senders) {
+                        let Some(sender) = sender.as_ref().filter(|_| first < part.len()) else {
+                            continue;
+                        };
+                        let last = part.len().min(first + size);
+                        // the pixels of a pass are unique, so --dedupe has nothing to drop
+                        let mut cmd = vec![];
+                        if offset && (args.offset_per_batch || first == 0) {
+                            cmd.extend_from_slice(&offset_cmd);
+                        }
+                        for pxl in &part[first..last] {
+                            cmd.extend(to_cmd(pxl));
+                        }
+                        if let (Some(Bytes(sentinel)), true) =
+                            (&args.final_sentinel, last == part.len())
+                        {
+                            cmd.extend_from_slice(sentinel);
+                        }
+                        let permit = budget
+                            .clone()
+                            .acquire_many_owned(cmd.len().clamp(1, cap) as u32)
+                            .await?;
+                        peak_queued = peak_queued.max(cap - budget.available_permits());
+                        // a worker that stopped doesn't need its batches anymore
+                        let _ = sender.send((cmd, permit));
+                    }
+                    if senders.iter().flatten().all(|sender| sender.is_closed()) {
+                        break 'generating;
+                    }
+                }
+            }
+            if !args.loops {
+                break;
+            }
+        }
+    }
+    drop(senders);
+
+    // wait for threads to end
+    let connections = handles.len();
+    let mut totals = Totals {
+        peak_queued,
+        ..Totals::default()
+    };
+    for handle in handles {
+        match handle.join() {
+            Ok(Some(progress)) => {
+                totals.commands += progress.commands;
+                totals.bytes += progress.bytes;
+                totals.resent += progress.resent;
+                totals.reconnects += progress.reconnects;
+                totals.failed += progress.stopped as usize;
+            }
+            // stopped for the resize
+            Ok(None) => {}
+            Err(_) => totals.failed += 1,
+        }
+    }
+    if let Some(watcher) = watcher {
+        watcher.abort();
+    }
+    if resized.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+    println!(
+        "sent {} commands ({} bytes) on {connections} connections with {} reconnects",
+        totals.commands, totals.bytes, totals.reconnects
+    );
+    if totals.resent > 0 {
+        println!(
+            "{} commands were sent again after a reconnect",
+            totals.resent
+        );
+    }
+    if let Some(cap) = streamed {
+        println!("at most {peak_queued} of {cap} bytes were queued");
+    }
+    if totals.failed > 0 {
+        println!(
+            "WARN: {} of {connections} workers stopped before they were done",
+            totals.failed
+        );
+    }
+
+    // animations end with the last frame
+    let mut painted = pixels(frames.len() - 1);
+    painted.retain(visible);
+    if args.check {
+        let mut stream = sink::connect(args).await?;
+        check(args, &mut stream, &painted).await?;
+    }
+    Ok(Some(totals))
+}
+
+/// paints the frames of a worker on a connection of its own until they are
+/// done, a lost connection is opened again without stopping the other workers
+async fn work(
+    args: &Arguments,
+    frames: &[Vec<Vec<u8>>],
+    start: tokio::time::Instant,
+    checkpoint: Option<&Path>,
+    batches: &[Vec<(u32, u32)>],
+    offset: Option<&[u8]>,
+    mut streamed: Option<Streamed>,
+) -> Progress {
+    // every worker reconnects on its own, the others keep painting
+    let mut progress = Progress::default();
+    let mut stream = match sink::connect_recorded(args).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("WARN: worker could not connect: {e}");
+            progress.stopped = true;
+            return progress;
+        }
+    };
+    let mut throttle = Throttle::new(args);
+    // the streamed batches that were possibly lost with the connection
+    let mut lost = VecDeque::new();
+    loop {
+        let buffered = stream.get_ref().send_buffer_size();
+        let painted = match (&mut streamed, checkpoint) {
+            (Some(batches), _) => {
+                paint_streamed(
+                    &mut stream,
+                    batches,
+                    &mut lost,
+                    buffered,
+                    &mut throttle,
+                    &mut progress,
+                )
+                .await
+            }
+            (None, Some(path)) => {
+                let task = &frames[0];
+                paint_checkpointed(
+                    &mut stream,
+                    task,
+                    batches,
+                    path,
+                    &mut throttle,
+                    &mut progress,
+                )
+                .await
+            }
+            (None, None) => {
+                animate(
+                    &mut stream,
+                    frames,
+                    args,
+                    start,
+                    &mut throttle,
+                    &mut progress,
+                )
+                .await
+            }
+        };
+        let Err(mut e) = painted else {
+            break;
+        };
+        loop {
+            if !reconnect(args, &mut stream, &*e).await {
+                println!("WARN: worker stopped: {e}");
+                progress.stopped = true;
+                return progress;
+            }
+            progress.reconnects += 1;
+            // the new connection doesn't know the OFFSET of the lost one, the
+            // batches after the first one of a pass are relative to it
+            let Some(offset) = offset else {
+                break;
+            };
+            match stream.write_all(offset).await {
+                Ok(()) => break,
+                Err(error) => e = error.into(),
+            }
+        }
+        if streamed.is_some() {
+            // the batches that were lost are sent again from lost
+            continue;
+        }
+        // only the commands that possibly didn't arrive are sent again
+        let task = match (checkpoint, args.framerate) {
+            (None, None) => frames.concat(),
+            (None, Some(_)) => frames[progress.frame % frames.len()].clone(),
+            (Some(_), _) => frames[0].clone(),
+        };
+        progress.rewind(&task, buffered);
+    }
+    if args.check {
+        // the response arrives after the server has processed the commands
+        stream.get_mut().pause_recording();
+        let synced = async {
+            stream.write_all(b"SIZE\n").await?;
+            stream.flush().await?;
+            read_response(&mut stream, "SIZE").await
+        };
+        if let Err(e) = synced.await {
+            println!("WARN: worker could not wait for the server: {e}");
+        }
+    }
+    progress
+}
+
+/// waits until the flag is set
+async fn stopped(flag: &AtomicBool) {
+    while !flag.load(Ordering::Relaxed) {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+/// the position of a pixel of the image in the order that is split evenly
+/// between the workers, so that every worker gets a part of the image
+fn split_key(mode: SplitMode, workers: usize, size: (u32, u32), pxl: (u32, u32)) -> [u32; 4] {
+    const TILE_SIZE: u32 = 16;
+
+    let (x, y) = pxl;
+    match mode {
+        SplitMode::Rows => [0, 0, y, x],
+        SplitMode::Columns => [0, 0, x, y],
+        SplitMode::Quadrants => {
+            // a grid with at least as many blocks as workers
+            let columns = (workers as f64).sqrt().ceil() as u64;
+            let rows = (workers as u64).div_ceil(columns);
+            let column = x as u64 * columns / size.0 as u64;
+            let row = y as u64 * rows / size.1 as u64;
+            [0, (row * columns + column) as u32, y, x]
+        }
+        SplitMode::Tiles => {
+            let tile = (y / TILE_SIZE) * size.0.div_ceil(TILE_SIZE) + x / TILE_SIZE;
+            [tile % workers as u32, tile, y, x]
+        }
+    }
+}
+
+/// blends the pixels of the frame with the background by their alpha
+/// the result is opaque and keeps 16 bit per channel
+fn over(frame: &image::DynamicImage, background: [u8; 4]) -> image::DynamicImage {
+    let source = frame.to_rgba16();
+    let background = background.map(|channel| channel as u32 * 257);
+    let blended = image::ImageBuffer::from_fn(source.width(), source.height(), |x, y| {
+        let pxl = source.get_pixel(x, y).0;
+        let alpha = pxl[3] as u32;
+        image::Rgb([0, 1, 2].map(|i| {
+            ((pxl[i] as u32 * alpha + background[i] * (65535 - alpha) + 32767) / 65535) as u16
+        }))
+    });
+    image::DynamicImage::ImageRgb16(blended)
+}
+
+/// inserts frames between every two frames that blend the first into the second
+/// a transition of n frames ends with the second frame, with loops the last
+/// frame is also blended into the first one
+fn fade(frames: &[image::DynamicImage], steps: u32, loops: bool) -> Vec<image::DynamicImage> {
+    let frames: Vec<_> = frames.iter().map(|frame| frame.to_rgba16()).collect();
+    let mut faded = vec![];
+    for (i, to) in frames.iter().enumerate() {
+        let from = match i {
+            0 if loops => frames.last(),
+            0 => None,
+            _ => Some(&frames[i - 1]),
+        };
+        if let Some(from) = from {
+            for step in 1..steps {
+                let blended = image::ImageBuffer::from_fn(to.width(), to.height(), |x, y| {
+                    let (a, b) = (from.get_pixel(x, y).0, to.get_pixel(x, y).0);
+                    image::Rgba([0, 1, 2, 3].map(|c| {
+                        ((a[c] as u32 * (steps - step) + b[c] as u32 * step) / steps) as u16
+                    }))
+                });
+                faded.push(image::DynamicImage::ImageRgba16(blended));
+            }
+        }
+        faded.push(image::DynamicImage::ImageRgba16(to.clone()));
+    }
+    faded
+}
+
+/// cuts a sprite sheet into its cells, row by row
+/// pixels that don't fill a whole cell at the right and bottom are dropped
+fn cells(
+    sheet: &image::DynamicImage,
+    (columns, rows): (u32, u32),
+) -> Result<Vec<image::DynamicImage>, Box<dyn Error>> {
+    let (width, height) = (sheet.width() / columns, sheet.height() / rows);
+    if width == 0 || height == 0 {
+        return Err(format!(
+            "sheet of {}x{} pixels is too small for {columns}x{rows} cells",
+            sheet.width(),
+            sheet.height()
+        )
+        .into());
+    }
+    Ok((0..rows)
+        .cartesian_product(0..columns)
+        .map(|(row, column)| sheet.crop_imm(column * width, row * height, width, height))
+        .collect())
+}
+
+/// returns the size of an image scaled so its larger side covers the fraction
+/// of the canvas and the position that centers it on the canvas
+fn cover(image: (u32, u32), canvas: (u32, u32), fraction: f64) -> ((u32, u32), (u32, u32)) {
+    let scale = fraction
+        * f64::min(
+            canvas.0 as f64 / image.0 as f64,
+            canvas.1 as f64 / image.1 as f64,
+        );
+    let width = (image.0 as f64 * scale).round() as u32;
+    let height = (image.1 as f64 * scale).round() as u32;
+    let centered = (
+        canvas.0.saturating_sub(width) / 2,
+        canvas.1.saturating_sub(height) / 2,
+    );
+    ((width, height), centered)
+}
+
+/// returns the position of an image at an edge of the canvas, for every axis
+/// that the alignment sets, images bigger than the canvas stay at 0
+fn aligned(
+    align: Align,
+    margin: u32,
+    image: (u32, u32),
+    canvas: (u32, u32),
+) -> (Option<u32>, Option<u32>) {
+    // 0 is the start of an axis, 1 the end and 2 the middle
+    let (horizontal, vertical) = match align {
+        Align::Left => (Some(0), None),
+        Align::Right => (Some(1), None),
+        Align::Top => (None, Some(0)),
+        Align::Bottom => (None, Some(1)),
+        Align::TopLeft => (Some(0), Some(0)),
+        Align::TopRight => (Some(1), Some(0)),
+        Align::BottomLeft => (Some(0), Some(1)),
+        Align::BottomRight => (Some(1), Some(1)),
+        Align::Center => (Some(2), Some(2)),
+    };
+    let place = |side, image: u32, canvas: u32| match side {
+        0 => margin.min(canvas.saturating_sub(image)),
+        1 => canvas.saturating_sub(image.saturating_add(margin)),
+        _ => canvas.saturating_sub(image) / 2,
+    };
+    (
+        horizontal.map(|side| place(side, image.0, canvas.0)),
+        vertical.map(|side| place(side, image.1, canvas.1)),
+    )
+}
+
+/// the number of workers for the pixels of an image, at most one per pixel,
+/// and the warning when that is fewer than the threads
+fn clamp_workers(threads: usize, pixel_count: usize) -> (usize, Option<String>) {
+    let workers = threads.min(pixel_count);
+    let warning = (workers < threads).then(|| {
+        format!("WARN: only {pixel_count} pixels to paint, using {workers} of {threads} threads")
+    });
+    (workers, warning)
+}
+
+/// the batches a worker of a streamed image gets while it paints, the permit
+/// keeps their bytes in the --max-buffer-bytes budget until they are written
+type Streamed = tokio::sync::mpsc::UnboundedReceiver<(Vec<u8>, tokio::sync::OwnedSemaphorePermit)>;
+
+/// sends the batches of a streamed image until the generation is done
+/// the batches that could still be in the send buffer of the connection are
+/// kept, they are moved to lost when the connection fails and sent again first
+async fn paint_streamed<S: Sink>(
+    stream: &mut S,
+    batches: &mut Streamed,
+    lost: &mut VecDeque<Vec<u8>>,
+    buffered: usize,
+    throttle: &mut Throttle,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    let mut in_buffer: VecDeque<Vec<u8>> = VecDeque::new();
+    let mut in_buffer_bytes = 0;
+    let painted = loop {
+        let (cmd, permit) = match lost.pop_front() {
+            Some(cmd) => (cmd, None),
+            None => match batches.recv().await {
+                Some((cmd, permit)) => (cmd, Some(permit)),
+                None => break stream.flush().await.map_err(Into::into),
+            },
+        };
+        throttle.wait().await;
+        let written = pixel(stream, &cmd).await;
+        let commands = protocol::split_commands(&cmd).count();
+        throttle.record(written.is_ok(), commands);
+        if let Err(e) = written {
+            lost.push_front(cmd);
+            break Err(e);
+        }
+        progress.sent(&cmd, commands);
+        drop(permit);
+        in_buffer_bytes += cmd.len();
+        in_buffer.push_back(cmd);
+        while in_buffer
+            .front()
+            .is_some_and(|cmd| in_buffer_bytes - cmd.len() >= buffered)
+        {
+            in_buffer_bytes -= in_buffer.pop_front().map_or(0, |cmd| cmd.len());
+        }
+    };
+    if painted.is_err() {
+        for cmd in in_buffer.into_iter().rev() {
+            progress.resend(&cmd);
+            lost.push_front(cmd);
+        }
+    }
+    painted
+}
+
+/// sends the batches of a still image and appends the pixels of the sent
+/// batches to the checkpoint, at most once per second
+/// after a failure it continues after the batches stored in the checkpoint
+async fn paint_checkpointed<S: Sink>(
+    stream: &mut S,
+    task: &[Vec<u8>],
+    batches: &[Vec<(u32, u32)>],
+    path: &Path,
+    throttle: &mut Throttle,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+    let mut checkpoint = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let mut sent = String::new();
+    let mut saved = tokio::time::Instant::now();
+    let pending = task.iter().zip(batches).enumerate().skip(progress.batch);
+    for (i, (cmd, pixels)) in pending {
+        throttle.wait().await;
+        let written = pixel(stream, cmd).await;
+        let commands = protocol::split_commands(cmd).count();
+        throttle.record(written.is_ok(), commands);
+        written?;
+        progress.sent(cmd, commands);
+        for (x, y) in pixels {
+            sent.push_str(&format!("{x} {y}\n"));
+        }
+        if saved.elapsed() >= INTERVAL {
+            // only pixels that left the client count as sent
+            stream.flush().await?;
+            checkpoint.write_all(sent.as_bytes()).await?;
+            sent.clear();
+            saved = tokio::time::Instant::now();
+            progress.batch = i + 1;
+        }
+    }
+    stream.flush().await?;
+    checkpoint.write_all(sent.as_bytes()).await?;
+    progress.batch = 0;
+    Ok(())
+}
+
+/// reads the pixels stored in a checkpoint, one "x y" per line
+/// a missing checkpoint is empty, so the first run can use the same file
+async fn load_checkpoint(path: &Path) -> Result<HashSet<(u32, u32)>, Box<dyn Error>> {
+    let checkpoint = match tokio::fs::read_to_string(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        checkpoint => checkpoint?,
+    };
+    checkpoint
+        .lines()
+        .map(|line| {
+            line.split_once(' ')
+                .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+                .ok_or_else(|| format!("invalid checkpoint entry: {line:?}").into())
+        })
+        .collect()
+}
+
+/// paints the frames of a task in order, once or, if loops is set, indefinitely
+/// with a framerate a frame is painted at its time, frames that are due while
+/// painting are dropped instead of delaying all following frames
+async fn animate<S: Sink>(
+    stream: &mut S,
+    frames: &[Vec<Vec<u8>>],
+    args: &Arguments,
+    start: tokio::time::Instant,
+    throttle: &mut Throttle,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    let loops = args.loops;
+    let Some(framerate) = args.framerate else {
+        return paint(stream, &frames.concat(), loops, throttle, progress).await;
+    };
+
+    let interval = std::time::Duration::from_secs_f64(1.0 / framerate);
+    loop {
+        let frame = progress.frame;
+        if !loops && frame >= frames.len() {
+            break;
+        }
+        tokio::time::sleep_until(start + interval.mul_f64(frame as f64)).await;
+        paint(
+            stream,
+            &frames[frame % frames.len()],
+            false,
+            throttle,
+            progress,
+        )
+        .await?;
+
+        if args.deterministic {
+            progress.frame += 1;
+        } else {
+            let due = (start.elapsed().as_secs_f64() / interval.as_secs_f64()) as usize;
+            progress.frame = due.max(frame + 1);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Command;
+    use clap::Parser;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    fn parse(line: &str) -> Arguments {
+        Arguments::try_parse_from(["pxlclnt"].into_iter().chain(line.split_whitespace())).unwrap()
+    }
+
+    #[test]
+    fn warns_when_there_are_fewer_pixels_than_threads() {
+        assert_eq!(
+            clamp_workers(100, 10),
+            (
+                10,
+                Some("WARN: only 10 pixels to paint, using 10 of 100 threads".into())
+            )
+        );
+        assert_eq!(clamp_workers(4, 10), (4, None));
+        assert_eq!(clamp_workers(10, 10), (10, None));
+    }
+
+    #[test]
+    fn covers_a_fraction_of_the_canvas_centered() {
+        assert_eq!(
+            cover((100, 50), (1000, 1000), 0.5),
+            ((500, 250), (250, 375))
+        );
+        assert_eq!(cover((200, 400), (800, 600), 1.0), ((300, 600), (250, 0)));
+        // small images are scaled up
+        assert_eq!(cover((2, 2), (10, 20), 0.5), ((5, 5), (2, 7)));
+    }
+
+    /// paints frames that consist of their number with --framerate 10 on a
+    /// reader that takes this long for every frame, returns when the frames
+    /// arrived in milliseconds after the start
+    async fn paced(slow: Duration) -> Vec<(u128, String)> {
+        let args = parse("-d localhost -t 1 -s 1 --framerate 10 rect 0 0 1 1 ff0000");
+        let frames: Vec<Vec<Vec<u8>>> = (0..10)
+            .map(|i| vec![format!("PX 0 0 {i:06}\n").into_bytes()])
+            .collect();
+        let start = tokio::time::Instant::now();
+        // the pipe holds one frame, so painting waits while the reader is slow
+        let (mut sink, reader) = tokio::io::duplex(frames[0][0].len());
+        let reading = tokio::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            let mut arrived = vec![];
+            while let Ok(Some(line)) = lines.next_line().await {
+                arrived.push((start.elapsed().as_millis(), line[7..].to_string()));
+                tokio::time::sleep(slow).await;
+            }
+            arrived
+        });
+        let mut progress = Progress::default();
+        let mut throttle = Throttle::default();
+        animate(
+            &mut sink,
+            &frames,
+            &args,
+            start,
+            &mut throttle,
+            &mut progress,
+        )
+        .await
+        .unwrap();
+        drop(sink);
+        reading.await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paints_frames_at_the_framerate() {
+        let arrived = paced(Duration::ZERO).await;
+        let expected: Vec<(u128, String)> = (0..10).map(|i| (i * 100, format!("{i:06}"))).collect();
+        assert_eq!(arrived, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_the_frames_of_a_slow_paint() {
+        let arrived = paced(Duration::from_millis(250)).await;
+        let frames: Vec<u32> = arrived
+            .iter()
+            .map(|(_, frame)| frame.parse().unwrap())
+            .collect();
+        assert!(frames.len() < 10, "{arrived:?}");
+        assert!(
+            frames.windows(2).all(|pair| pair[0] < pair[1]),
+            "{arrived:?}"
+        );
+        // queued frames would take 10 times as long as the slow reader
+        assert!(arrived.last().unwrap().0 < 2000, "{arrived:?}");
+    }
+
+    #[test]
+    fn blends_half_transparent_pixels_over_the_background() {
+        let colors = [[255, 0, 0, 128], [255, 0, 0, 0], [0, 255, 0, 255]];
+        let frame = image::RgbaImage::from_fn(3, 1, |x, _| image::Rgba(colors[x as usize]));
+        let blended = over(&image::DynamicImage::ImageRgba8(frame), [0, 0, 255, 255]).to_rgb8();
+        assert_eq!(blended.get_pixel(0, 0).0, [128, 0, 127]);
+        assert_eq!(blended.get_pixel(1, 0).0, [0, 0, 255]);
+        assert_eq!(blended.get_pixel(2, 0).0, [0, 255, 0]);
+    }
+
+    #[test]
+    fn aligns_the_image_to_the_edges_with_a_margin() {
+        let canvas = (100, 50);
+        assert_eq!(aligned(Align::Right, 10, (20, 5), canvas), (Some(70), None));
+        assert_eq!(
+            aligned(Align::Bottom, 10, (20, 5), canvas),
+            (None, Some(35))
+        );
+        assert_eq!(
+            aligned(Align::BottomRight, 10, (20, 5), canvas),
+            (Some(70), Some(35))
+        );
+        assert_eq!(
+            aligned(Align::TopLeft, 10, (20, 5), canvas),
+            (Some(10), Some(10))
+        );
+        // the margin is ignored by the center and images bigger than the canvas stay at 0
+        assert_eq!(
+            aligned(Align::Center, 10, (20, 6), canvas),
+            (Some(40), Some(22))
+        );
+        assert_eq!(
+            aligned(Align::BottomRight, 10, (200, 50), canvas),
+            (Some(0), Some(0))
+        );
+        // margins bigger than the canvas push the image to the opposite edge
+        assert_eq!(
+            aligned(Align::BottomRight, u32::MAX, (20, 5), canvas),
+            (Some(0), Some(0))
+        );
+        assert_eq!(
+            aligned(Align::TopLeft, u32::MAX, (20, 5), canvas),
+            (Some(80), Some(45))
+        );
+        let args = parse("-d x -t 1 -s 1 image 0 0 x.png --align right --margin 4294967295");
+        let Command::Image(img) = &args.command else {
+            unreachable!()
+        };
+        assert_eq!(img.margin, u32::MAX);
+    }
+
+    #[test]
+    fn rejects_sheets_smaller_than_the_cells() {
+        let small = image::DynamicImage::new_rgb8(3, 1);
+        assert!(cells(&small, (4, 1)).is_err());
+        assert_eq!(cells(&small, (3, 1)).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,267 @@
+use std::{collections::HashMap, error::Error, fmt};
+
+use nom::{
+    bytes::complete::tag,
+    character::complete,
+    combinator::opt,
+    sequence::{preceded, separated_pair, terminated, tuple},
+};
+
+/// a response of the server that could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    response: String,
+}
+
+impl ParseError {
+    pub(crate) fn new(response: impl Into<String>) -> Self {
+        Self {
+            response: response.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unexpected response from server: {:?}", self.response)
+    }
+}
+
+impl Error for ParseError {}
+
+/// length of a binary PB command, "PB", x and y as u16 and rgba
+pub const PB_LEN: usize = 10;
+
+/// splits a stream of commands into single commands
+/// text commands end with a line break, binary PB commands have a fixed length
+pub fn split_commands(mut input: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        if input.is_empty() {
+            return None;
+        }
+        let len = if input.starts_with(b"PB") {
+            PB_LEN.min(input.len())
+        } else {
+            input
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(input.len(), |i| i + 1)
+        };
+        let (cmd, rest) = input.split_at(len);
+        input = rest;
+        Some(cmd)
+    })
+}
+
+/// the pixel a command paints, None for commands that don't paint
+pub fn target(cmd: &[u8]) -> Option<(u32, u32)> {
+    if cmd.len() == PB_LEN && cmd.starts_with(b"PB") {
+        let x = u16::from_le_bytes([cmd[2], cmd[3]]);
+        let y = u16::from_le_bytes([cmd[4], cmd[5]]);
+        return Some((x as u32, y as u32));
+    }
+    let (x, y, _) = parse_pixel(std::str::from_utf8(cmd).ok()?).ok()?;
+    Some((x, y))
+}
+
+/// returns the color of a command that sets a pixel as hex
+/// PB commands have rgba colors
+pub fn color(cmd: &[u8]) -> Option<String> {
+    if cmd.len() == PB_LEN && cmd.starts_with(b"PB") {
+        return Some(cmd[6..].iter().map(|byte| format!("{byte:02x}")).collect());
+    }
+    let (_, _, color) = parse_pixel(std::str::from_utf8(cmd).ok()?).ok()?;
+    Some(color.to_string())
+}
+
+/// drops the commands that are overwritten by a later command for the same pixel
+/// the batches keep their order, batches without commands are removed
+pub fn dedupe(task: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut last = HashMap::new();
+    let cmds = task.iter().flat_map(|batch| split_commands(batch));
+    for (i, cmd) in cmds.enumerate() {
+        if let Some(pixel) = target(cmd) {
+            last.insert(pixel, i);
+        }
+    }
+
+    let mut i = 0;
+    let mut deduped = Vec::with_capacity(task.len());
+    for batch in task {
+        let mut kept = Vec::with_capacity(batch.len());
+        for cmd in split_commands(batch) {
+            if target(cmd).is_none_or(|pixel| last[&pixel] == i) {
+                kept.extend_from_slice(cmd);
+            }
+            i += 1;
+        }
+        if !kept.is_empty() {
+            deduped.push(kept);
+        }
+    }
+    deduped
+}
+
+/// parses the response to SIZE, e.g. "SIZE 1920 1080\n"
+/// some servers add the color depth in bits like "SIZE 1920 1080 24\n",
+/// further fields are ignored
+pub fn parse_size(input: &str) -> Result<(u32, u32, Option<u32>), ParseError> {
+    fn parse(input: &str) -> nom::IResult<&str, (u32, u32, Option<u32>)> {
+        let (input, (width, height)) = preceded(
+            terminated(tag("SIZE"), complete::space1),
+            separated_pair(complete::u32, complete::space1, complete::u32),
+        )(input)?;
+        let (input, depth) = opt(preceded(complete::space1, complete::u32))(input)?;
+        let (input, _) = terminated(
+            opt(preceded(complete::space1, complete::not_line_ending)),
+            complete::line_ending,
+        )(input)?;
+        Ok((input, (width, height, depth)))
+    }
+
+    parse(input)
+        .map(|(_, size)| size)
+        .map_err(|_| ParseError::new(input))
+}
+
+/// parses the response to reading a pixel, e.g. "PX 10 20 ff0000\n"
+pub fn parse_pixel(input: &str) -> Result<(u32, u32, &str), ParseError> {
+    fn parse(input: &str) -> nom::IResult<&str, (u32, u32, &str)> {
+        preceded(
+            terminated(tag("PX"), complete::space1),
+            tuple((
+                terminated(complete::u32, complete::space1),
+                terminated(complete::u32, complete::space1),
+                terminated(complete::hex_digit1, complete::line_ending),
+            )),
+        )(input)
+    }
+
+    parse(input)
+        .map(|(_, pixel)| pixel)
+        .map_err(|_| ParseError::new(input))
+}
+
+/// parses the response to HELP into printable text
+/// control characters except line breaks and tabs are removed
+pub fn parse_help(input: &[u8]) -> Result<String, ParseError> {
+    let text =
+        std::str::from_utf8(input).map_err(|_| ParseError::new(String::from_utf8_lossy(input)))?;
+    Ok(text
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t'))
+        .collect())
+}
+
+/// checks if a HELP text mentions a command, ignoring the case
+pub fn help_mentions(help: &str, command: &str) -> bool {
+    help.split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| word.eq_ignore_ascii_case(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_size() {
+        assert_eq!(parse_size("SIZE 1920 1080\n"), Ok((1920, 1080, None)));
+        assert_eq!(parse_size("SIZE 800 600\r\n"), Ok((800, 600, None)));
+    }
+
+    #[test]
+    fn parses_the_depth_of_size() {
+        assert_eq!(
+            parse_size("SIZE 1920 1080 24\n"),
+            Ok((1920, 1080, Some(24)))
+        );
+        assert_eq!(parse_size("SIZE 64 48 48\r\n"), Ok((64, 48, Some(48))));
+        // fields after the depth or that are no depth are ignored
+        assert_eq!(parse_size("SIZE 64 48 24 rgb\n"), Ok((64, 48, Some(24))));
+        assert_eq!(parse_size("SIZE 64 48 rgb\n"), Ok((64, 48, None)));
+    }
+
+    #[test]
+    fn rejects_broken_responses() {
+        // inputs that made the old parser with take(5) panic or accept garbage
+        let broken = [
+            "",
+            "SIZE",
+            "SIZ€ 1 2\n",
+            "€€€€€€",
+            "SIZE 1920\n",
+            "SIZE 1920 1080",
+            "SIZE -1 2\n",
+            "SIZE 99999999999 1\n",
+            "XXXXX1 2\n",
+            "PX 1 2 ff0000\n",
+        ];
+        for input in broken {
+            assert_eq!(parse_size(input), Err(ParseError::new(input)), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn parses_pixels() {
+        assert_eq!(parse_pixel("PX 10 20 ff0000\n"), Ok((10, 20, "ff0000")));
+        for input in ["", "PX", "PX 10 20\n", "PX 10 20 zz\n", "PX € 1 ff\n"] {
+            assert!(parse_pixel(input).is_err(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn removes_control_characters_from_help() {
+        assert_eq!(
+            parse_help(b"HELP\x1b[31m PX\x00\n"),
+            Ok("HELP[31m PX\n".into())
+        );
+        assert!(parse_help(&[0xff, 0xfe, b'\n']).is_err());
+    }
+
+    #[test]
+    fn mentions_commands_as_words() {
+        assert!(help_mentions("commands: px, size, offset x y", "OFFSET"));
+        assert!(!help_mentions("commands: PX, SIZE, NOOFFSET", "OFFSET"));
+    }
+
+    #[test]
+    fn keeps_the_last_color_of_every_pixel() {
+        let task = vec![
+            b"PX 1 1 ff0000\nPX 2 2 ff0000\n".to_vec(),
+            b"PX 1 1 00ff00\nOFFSET 5 5\n".to_vec(),
+            b"PX 2 2 0000ff\n".to_vec(),
+            b"PX 1 1 ffffff\n".to_vec(),
+        ];
+        assert_eq!(
+            dedupe(&task),
+            [
+                b"OFFSET 5 5\n".to_vec(),
+                b"PX 2 2 0000ff\n".to_vec(),
+                b"PX 1 1 ffffff\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn dedupes_binary_commands() {
+        let pb = |x: u8, color: u8| vec![b'P', b'B', x, 0, 0, 0, color, 0, 0, 255];
+        let task = vec![[pb(1, 1), pb(2, 2), pb(1, 3)].concat()];
+        assert_eq!(dedupe(&task), [[pb(2, 2), pb(1, 3)].concat()]);
+        assert_eq!(target(&pb(1, 3)), Some((1, 0)));
+        assert_eq!(color(&pb(1, 3)), Some("030000ff".into()));
+    }
+
+    #[test]
+    fn splits_text_and_binary_commands() {
+        let stream = b"PX 0 0 ff\nPB\x01\x00\x02\x00\x03\x04\x05\x06PX 1 1 00";
+        let cmds: Vec<&[u8]> = split_commands(stream).collect();
+        assert_eq!(
+            cmds,
+            [
+                &b"PX 0 0 ff\n"[..],
+                b"PB\x01\x00\x02\x00\x03\x04\x05\x06",
+                b"PX 1 1 00"
+            ]
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Deserializer};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    finish_pass, load_image, parse_color, protocol, rect_cmds, rng, sink, size, Arguments, Pixel,
+    Rect, Script,
+};
+
+/// an operation of a script, an object with the name of the operation as key
+/// e.g. {"rect": {"start_x": 0, "start_y": 0, "end_x": 10, "end_y": 10, "color": "ff0000"}}
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+enum Step {
+    Pixel {
+        x: u32,
+        y: u32,
+        #[serde(deserialize_with = "color")]
+        color: String,
+    },
+    Rect {
+        start_x: u32,
+        start_y: u32,
+        end_x: u32,
+        end_y: u32,
+        #[serde(deserialize_with = "color")]
+        color: String,
+    },
+    /// paths are relative to the script
+    Image {
+        x: u32,
+        y: u32,
+        path: PathBuf,
+    },
+    Wait {
+        ms: u64,
+    },
+    /// repeats the steps, without times indefinitely
+    Loop {
+        times: Option<u32>,
+        steps: Vec<Step>,
+    },
+}
+
+/// a script that doesn't match the schema, shows the line of the error
+struct InvalidScript {
+    path: PathBuf,
+    line: String,
+    error: serde_json::Error,
+}
+
+impl fmt::Display for InvalidScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = " ".repeat(self.error.column().saturating_sub(1));
+        write!(
+            f,
+            "invalid script {}: {}\n{}\n{marker}^",
+            self.path.display(),
+            self.error,
+            self.line
+        )
+    }
+}
+
+// main prints errors with Debug, the line would be escaped otherwise
+impl fmt::Debug for InvalidScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Error for InvalidScript {}
+
+fn color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let color = String::deserialize(deserializer)?;
+    parse_color(&color).map_err(serde::de::Error::custom)
+}
+
+/// reads the script and runs its steps in order on one connection
+pub async fn run(args: &Arguments, script: &Script) -> Result<(), Box<dyn Error>> {
+    let source = tokio::fs::read_to_string(&script.path).await?;
+    let steps: Vec<Step> = serde_json::from_str(&source).map_err(|e| InvalidScript {
+        path: script.path.clone(),
+        line: source
+            .lines()
+            .nth(e.line().saturating_sub(1))
+            .unwrap_or("")
+            .to_string(),
+        error: e,
+    })?;
+
+    let base = script.path.parent().unwrap_or(Path::new("."));
+    let mut stream = sink::connect_recorded(args).await?;
+    let mut pass = vec![];
+    execute(args, &mut stream, &mut pass, &steps, base).await?;
+    send(args, &mut stream, &mut pass).await
+}
+
+/// sends the commands of a pass, a pass ends with a wait, a repetition of a
+/// loop or the end of the script
+async fn send(
+    args: &Arguments,
+    stream: &mut impl sink::Sink,
+    pass: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let mut task = vec![std::mem::take(pass)];
+    finish_pass(args, &mut task);
+    stream.write_all(&task.concat()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// appends commands to the pass, with --max-buffer-bytes the buffered commands
+/// are sent when they reach the limit, --dedupe only drops commands among them
+async fn push(
+    args: &Arguments,
+    stream: &mut impl sink::Sink,
+    pass: &mut Vec<u8>,
+    cmds: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    pass.extend_from_slice(cmds);
+    if args.max_buffer_bytes.is_some_and(|max| pass.len() >= max) {
+        let mut buffered = std::mem::take(pass);
+        if args.dedupe {
+            buffered = protocol::dedupe(&[buffered]).concat();
+        }
+        stream.write_all(&buffered).await?;
+    }
+    Ok(())
+}
+
+async fn execute(
+    args: &Arguments,
+    stream: &mut impl sink::Sink,
+    pass: &mut Vec<u8>,
+    steps: &[Step],
+    base: &Path,
+) -> Result<(), Box<dyn Error>> {
+    for step in steps {
+        match step {
+            Step::Pixel { x, y, color } => {
+                let pxl = Pixel {
+                    x: *x,
+                    y: *y,
+                    color: color.clone(),
+                };
+                push(args, stream, pass, &pxl.encode(args)).await?;
+            }
+            Step::Rect {
+                start_x,
+                start_y,
+                end_x,
+                end_y,
+                color,
+            } => {
+                let rect = Rect {
+                    start_x: *start_x,
+                    start_y: *start_y,
+                    end_x: *end_x,
+                    end_y: *end_y,
+                    color: color.clone(),
+                    noise: 0,
+                };
+                for batch in rect_cmds(&rect, args, &mut rng(args)) {
+                    push(args, stream, pass, &batch).await?;
+                }
+            }
+            Step::Image { x, y, path } => {
+                let canvas_limit = size(args).await?;
+                let image = load_image(&base.join(path))?.swap_remove(0).to_rgb8();
+                for (px, py, pxl) in image.enumerate_pixels() {
+                    let pxl = Pixel {
+                        x: x + px,
+                        y: y + py,
+                        color: format!("{:02x}{:02x}{:02x}", pxl.0[0], pxl.0[1], pxl.0[2]),
+                    };
+                    if pxl.x < canvas_limit.0 && pxl.y < canvas_limit.1 {
+                        push(args, stream, pass, &pxl.encode(args)).await?;
+                    }
+                }
+            }
+            Step::Wait { ms } => {
+                // everything before the wait should be visible while waiting
+                send(args, stream, pass).await?;
+                tokio::time::sleep(Duration::from_millis(*ms)).await;
+            }
+            Step::Loop { times, steps } => {
+                let mut done = 0;
+                while times.is_none_or(|times| done < times) {
+                    Box::pin(execute(args, stream, pass, steps, base)).await?;
+                    send(args, stream, pass).await?;
+                    done += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn parse(line: &str) -> Arguments {
+        Arguments::try_parse_from(["pxlclnt"].into_iter().chain(line.split_whitespace())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn executes_the_steps_in_order() {
+        let steps: Vec<Step> = serde_json::from_str(
+            r#"[
+                {"pixel": {"x": 5, "y": 6, "color": "ffffff"}},
+                {"rect": {"start_x": 0, "start_y": 0, "end_x": 2, "end_y": 1, "color": "ff0000"}},
+                {"wait": {"ms": 1}},
+                {"loop": {"times": 2, "steps": [{"pixel": {"x": 1, "y": 1, "color": "00ff00"}}]}}
+            ]"#,
+        )
+        .unwrap();
+        let args = parse("-d localhost -t 1 -s 1 size");
+        let mut sink = vec![];
+        let mut pass = vec![];
+        execute(&args, &mut sink, &mut pass, &steps, Path::new("."))
+            .await
+            .unwrap();
+        send(&args, &mut sink, &mut pass).await.unwrap();
+        assert_eq!(
+            String::from_utf8(sink).unwrap(),
+            "PX 5 6 ffffff\nPX 0 0 ff0000\nPX 1 0 ff0000\nPX 1 1 00ff00\nPX 1 1 00ff00\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn shows_the_line_of_an_invalid_step() {
+        let path =
+            std::env::temp_dir().join(format!("pxlclnt-{}-invalid.json", std::process::id()));
+        std::fs::write(
+            &path,
+            "[\n  {\"pixel\": {\"x\": 1, \"y\": 2, \"color\": \"red\"}}\n]",
+        )
+        .unwrap();
+        let args = parse(&format!("-d localhost -t 1 -s 1 script {}", path.display()));
+        let crate::Command::Script(script) = &args.command else {
+            unreachable!()
+        };
+        let e = run(&args, script).await.unwrap_err().to_string();
+        assert!(
+            e.starts_with(&format!("invalid script {}", path.display())),
+            "{e}"
+        );
+        assert!(
+            e.contains("\n  {\"pixel\": {\"x\": 1, \"y\": 2, \"color\": \"red\"}}\n"),
+            "{e}"
+        );
+        assert!(e.ends_with('^'), "{e}");
+    }
+}
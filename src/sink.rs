@@ -0,0 +1,484 @@
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::mpsc,
+    task::{ready, Context, Poll},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf},
+    net::{TcpSocket, TcpStream},
+    time::Instant,
+};
+
+use crate::Arguments;
+
+/// upper limit of the time between two connection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// how long --expect-banner waits for the greeting of the server
+const BANNER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// number of writes after which the throttle checks how many of them failed
+const THROTTLE_WINDOW: u32 = 50;
+
+/// fraction of failed writes above which the writes are slowed down
+const THROTTLE_ERROR_RATE: f64 = 0.05;
+
+/// upper limit of the time between two writes of a throttled connection
+const MAX_THROTTLE: Duration = Duration::from_secs(1);
+
+/// destination of pixelflut commands
+/// anything that can be written to asynchronously works as a sink,
+/// e.g. a tcp connection, stdout or a Vec<u8>
+pub trait Sink: AsyncWrite + Unpin + Send {}
+
+impl<T> Sink for T where T: AsyncWrite + Unpin + Send {}
+
+/// a sink that can also be read from, needed for commands with responses
+pub trait Connection: Sink + AsyncBufRead {}
+
+impl<T> Connection for T where T: Sink + AsyncBufRead {}
+
+/// a tcp connection to the server
+/// a recorded connection also appends everything it sends to the recording
+pub struct Socket {
+    stream: TcpStream,
+    recording: Option<Recording>,
+}
+
+/// with --record-timed every write is stored as "@<microseconds> <length>\n"
+/// followed by the bytes, the microseconds are counted since the unix epoch
+/// so the writes of every connection share the same clock
+/// the file is written by a thread of its own, so sending never waits for it
+struct Recording {
+    records: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<thread::JoinHandle<()>>,
+    timed: bool,
+    paused: bool,
+}
+
+impl Recording {
+    fn open(path: &Path, timed: bool) -> io::Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (records, received) = mpsc::channel::<Vec<u8>>();
+        let writer = thread::spawn(move || {
+            // one write per record, so the records of connections don't interleave
+            for record in received {
+                if let Err(e) = file.write_all(&record) {
+                    println!("WARN: could not write the recording: {e}");
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            records: Some(records),
+            writer: Some(writer),
+            timed,
+            paused: false,
+        })
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        if self.paused {
+            return;
+        }
+        let record = match self.timed {
+            true => {
+                let time = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                let mut record = format!("@{} {}\n", time.as_micros(), bytes.len()).into_bytes();
+                record.extend_from_slice(bytes);
+                record
+            }
+            false => bytes.to_vec(),
+        };
+        // a writer that stopped already warned about the reason
+        if let Some(records) = &self.records {
+            let _ = records.send(record);
+        }
+    }
+}
+
+impl Drop for Recording {
+    /// waits until every record is in the file
+    fn drop(&mut self) {
+        drop(self.records.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Socket {
+    /// bytes the operating system buffers for sending, they are lost with the
+    /// connection if they were not sent yet
+    pub fn send_buffer_size(&self) -> usize {
+        socket2::SockRef::from(&self.stream)
+            .send_buffer_size()
+            .unwrap_or(0)
+    }
+
+    /// with --record appends everything that is sent from now on to the recording
+    /// only connections that paint are recorded, a replay doesn't read the
+    /// responses to queries like SIZE or reading pixels back
+    pub fn record(&mut self, args: &Arguments) -> io::Result<()> {
+        let Some(path) = &args.record else {
+            return Ok(());
+        };
+        match &mut self.recording {
+            Some(recording) => recording.paused = false,
+            None => self.recording = Some(Recording::open(path, args.record_timed)?),
+        }
+        Ok(())
+    }
+
+    /// stops recording until record is called again, e.g. to read pixels back
+    pub fn pause_recording(&mut self) {
+        if let Some(recording) = &mut self.recording {
+            recording.paused = true;
+        }
+    }
+
+    /// if what is sent is currently recorded
+    pub fn is_recording(&self) -> bool {
+        self.recording
+            .as_ref()
+            .is_some_and(|recording| !recording.paused)
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.stream).poll_write(cx, buf))?;
+        if let Some(recording) = &mut self.recording {
+            recording.record(&buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// opens a connection to the pixelflut server
+/// with --wait-for-server refused connections are retried until it runs out
+/// with --send-buffer the send buffer of the socket gets that size
+pub async fn connect(args: &Arguments) -> io::Result<BufReader<Socket>> {
+    let stream = match args.wait_for_server {
+        None => open(args).await?,
+        Some(secs) => {
+            let deadline = Instant::now() + Duration::from_secs(secs);
+            let mut backoff = Duration::from_millis(100);
+            loop {
+                match open(args).await {
+                    Ok(stream) => break stream,
+                    // e.g. an unknown host won't start to exist by waiting
+                    Err(e)
+                        if e.kind() == io::ErrorKind::ConnectionRefused
+                            && Instant::now() + backoff < deadline =>
+                    {
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    };
+
+    if let Some(bytes) = args.send_buffer {
+        socket2::SockRef::from(&stream).set_send_buffer_size(bytes)?;
+    }
+
+    let mut stream = BufReader::new(Socket {
+        stream,
+        recording: None,
+    });
+    if args.expect_banner {
+        let mut banner = String::new();
+        tokio::time::timeout(BANNER_TIMEOUT, stream.read_line(&mut banner))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("server sent no banner within {}s", BANNER_TIMEOUT.as_secs()),
+                )
+            })??;
+    }
+    Ok(stream)
+}
+
+/// opens a connection that paints, with --record it is recorded
+pub async fn connect_recorded(args: &Arguments) -> io::Result<BufReader<Socket>> {
+    let mut stream = connect(args).await?;
+    stream.get_mut().record(args)?;
+    Ok(stream)
+}
+
+/// opens a tcp connection to the server
+/// with --bind it is sent from that local address, e.g. to choose the interface
+async fn open(args: &Arguments) -> io::Result<TcpStream> {
+    let Some(local) = args.bind else {
+        return TcpStream::connect(&args.domain).await;
+    };
+
+    // only an address of the same family as the local one can be reached
+    let remote = tokio::net::lookup_host(&args.domain)
+        .await?
+        .find(|remote| remote.is_ipv4() == local.is_ipv4())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!(
+                    "{} has no address that can be reached from {local}",
+                    args.domain
+                ),
+            )
+        })?;
+    let socket = match local {
+        std::net::IpAddr::V4(_) => TcpSocket::new_v4()?,
+        std::net::IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(local, 0))?;
+    socket.connect(remote).await
+}
+
+/// checks if the server closed the connection without waiting
+/// unread data means that the connection is still open
+pub async fn closed<C: Connection>(stream: &mut C) -> bool {
+    matches!(
+        tokio::time::timeout(Duration::ZERO, stream.fill_buf()).await,
+        Ok(Ok([]))
+    )
+}
+
+/// paces the writes of a connection
+/// with --burst and --idle it pauses after every burst of commands
+/// it also slows down the writes when many of them fail, which usually means
+/// that the server is overwhelmed or limits the rate, the delay doubles with
+/// every window above the error rate and halves with every window without errors
+/// only a connection that is opened again after a failed write, with --reconnect,
+/// writes often enough after failures to be slowed down
+#[derive(Debug, Default)]
+pub struct Throttle {
+    writes: u32,
+    failures: u32,
+    delay: Duration,
+    burst: Option<(usize, Duration)>,
+    sent: usize,
+}
+
+impl Throttle {
+    pub fn new(args: &Arguments) -> Self {
+        Self {
+            burst: args
+                .burst
+                .zip(args.idle)
+                .map(|(burst, idle)| (burst, Duration::from_millis(idle))),
+            ..Self::default()
+        }
+    }
+
+    /// waits before the next write if the connection is throttled or a burst is over
+    /// bursts end after the write that reaches the number of commands
+    pub async fn wait(&mut self) {
+        if let Some((burst, idle)) = self.burst {
+            if self.sent >= burst {
+                tokio::time::sleep(idle).await;
+                self.sent = 0;
+            }
+        }
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+    }
+
+    /// counts a write of some commands and adjusts the delay at the end of a window
+    pub fn record(&mut self, success: bool, commands: usize) {
+        self.sent += commands;
+        self.writes += 1;
+        if !success {
+            self.failures += 1;
+        }
+        if self.writes < THROTTLE_WINDOW {
+            return;
+        }
+
+        let rate = self.failures as f64 / self.writes as f64;
+        if rate > THROTTLE_ERROR_RATE {
+            self.delay = (self.delay * 2).clamp(Duration::from_millis(1), MAX_THROTTLE);
+            println!(
+                "WARN: {:.0}% of the writes failed, the server seems overwhelmed, slowing down to {:?} between writes",
+                rate * 100.0,
+                self.delay
+            );
+        } else if self.failures == 0 && !self.delay.is_zero() {
+            self.delay /= 2;
+            if self.delay < Duration::from_millis(1) {
+                self.delay = Duration::ZERO;
+            }
+        }
+        self.writes = 0;
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn parse(line: &str) -> Arguments {
+        Arguments::try_parse_from(["pxlclnt"].into_iter().chain(line.split_whitespace())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn waits_until_the_server_accepts() {
+        // a free port that refuses connections until the listener starts
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let delay = Duration::from_millis(300);
+        let listening = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let listener = TcpListener::bind(address).await.unwrap();
+            listener.accept().await.unwrap()
+        });
+
+        let refused = connect(&parse(&format!("-d {address} -t 1 -s 1 size"))).await;
+        assert_eq!(
+            refused.err().unwrap().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+
+        let started = Instant::now();
+        let args = parse(&format!("-d {address} -t 1 -s 1 --wait-for-server 5 size"));
+        connect(&args).await.unwrap();
+        assert!(started.elapsed() >= delay);
+        listening.await.unwrap();
+    }
+
+    /// records a window of writes of which this many failed
+    fn window(throttle: &mut Throttle, failures: u32) {
+        for i in 0..THROTTLE_WINDOW {
+            throttle.record(i >= failures, 1);
+        }
+    }
+
+    #[test]
+    fn slows_down_while_writes_fail() {
+        let mut throttle = Throttle::default();
+        window(&mut throttle, 2);
+        assert_eq!(throttle.delay, Duration::ZERO);
+        window(&mut throttle, 5);
+        assert_eq!(throttle.delay, Duration::from_millis(1));
+        window(&mut throttle, 5);
+        assert_eq!(throttle.delay, Duration::from_millis(2));
+        for _ in 0..20 {
+            window(&mut throttle, THROTTLE_WINDOW);
+        }
+        assert_eq!(throttle.delay, MAX_THROTTLE);
+
+        // a window with a few failures keeps the delay
+        window(&mut throttle, 1);
+        assert_eq!(throttle.delay, MAX_THROTTLE);
+        window(&mut throttle, 0);
+        assert_eq!(throttle.delay, MAX_THROTTLE / 2);
+        for _ in 0..20 {
+            window(&mut throttle, 0);
+        }
+        assert_eq!(throttle.delay, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_after_a_burst() {
+        let args = parse("-d localhost -t 1 -s 1 --burst 10 --idle 500 size");
+        let mut throttle = Throttle::new(&args);
+        let started = Instant::now();
+        for _ in 0..3 {
+            throttle.wait().await;
+            throttle.record(true, 5);
+        }
+        // the third write starts after the burst of 10 commands
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn sets_the_send_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let buffer = |line: String| async move {
+            let stream = connect(&parse(&line)).await.unwrap();
+            socket2::SockRef::from(&stream.get_ref().stream)
+                .send_buffer_size()
+                .unwrap()
+        };
+        let default = buffer(format!("-d {address} -t 1 -s 1 size")).await;
+        let set = buffer(format!("-d {address} -t 1 -s 1 --send-buffer 12345 size")).await;
+        assert_ne!(set, default);
+        // linux doubles the size for its bookkeeping
+        assert!([12345, 2 * 12345].contains(&set), "{set}");
+    }
+
+    #[tokio::test]
+    async fn sends_from_the_bound_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let args = parse(&format!("-d {address} -t 1 -s 1 --bind 127.0.0.2 size"));
+        let stream = connect(&args).await.unwrap();
+        let local = stream.get_ref().stream.local_addr().unwrap();
+        assert_eq!(local.ip().to_string(), "127.0.0.2");
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, local);
+
+        let args = parse(&format!("-d {address} -t 1 -s 1 --bind ::1 size"));
+        let unreachable = connect(&args).await.err().unwrap();
+        assert_eq!(unreachable.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_if_the_server_sends_no_banner() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let silent = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let args = parse(&format!("-d {address} -t 1 -s 1 --expect-banner size"));
+        let started = Instant::now();
+        let error = connect(&args).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(error.to_string().contains("no banner"), "{error}");
+        assert!(started.elapsed() >= BANNER_TIMEOUT);
+        silent.await.unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+use font8x8::legacy::BASIC_LEGACY;
+
+/// width and height of a single character in pixels
+pub const GLYPH_SIZE: u32 = 8;
+
+/// returns the bitmap of a character, one byte per row
+/// the lowest bit of a row is the leftmost pixel
+fn glyph(c: char) -> [u8; 8] {
+    match c {
+        '\t' => BASIC_LEGACY[' ' as usize],
+        c if c.is_ascii() => BASIC_LEGACY[c as usize],
+        _ => BASIC_LEGACY['?' as usize],
+    }
+}
+
+/// renders lines of text into a block of `columns` x `lines.len()` characters
+/// calls `paint` for every pixel of the block with the pixel position relative
+/// to the upper left corner and whether the pixel is part of a character
+pub fn render(lines: &[String], columns: u32, mut paint: impl FnMut(u32, u32, bool)) {
+    for (row, line) in lines.iter().enumerate() {
+        let mut chars = line.chars();
+        for column in 0..columns {
+            let bitmap = glyph(chars.next().unwrap_or(' '));
+            for (dy, bits) in bitmap.iter().enumerate() {
+                for dx in 0..GLYPH_SIZE {
+                    paint(
+                        column * GLYPH_SIZE + dx,
+                        row as u32 * GLYPH_SIZE + dy as u32,
+                        bits >> dx & 1 == 1,
+                    );
+                }
+            }
+        }
+    }
+}